@@ -0,0 +1,3 @@
+ALTER TABLE sessions
+    ADD COLUMN sexpr_limit INTEGER NOT NULL DEFAULT 16,
+    ADD COLUMN value_limit INTEGER NOT NULL DEFAULT 64;
@@ -0,0 +1,27 @@
+-- Each change to a session's code is a revision. Undoing
+-- moves back to the parent, so revisions form a tree.
+CREATE TABLE revisions (
+    id BIGSERIAL PRIMARY KEY,
+    thread_id TEXT NOT NULL REFERENCES sessions (thread_id) ON DELETE CASCADE,
+    parent_id BIGINT REFERENCES revisions (id),
+    source_code TEXT NOT NULL,
+    author_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX revisions_thread_id_idx ON revisions (thread_id);
+
+-- A session without a current revision has no code yet.
+ALTER TABLE sessions
+    ADD COLUMN revision_id BIGINT REFERENCES revisions (id);
+
+-- Keep the code of existing sessions as their first revision.
+INSERT INTO revisions (thread_id, source_code, author_id)
+SELECT thread_id, source_code, user_ids[1]
+FROM sessions
+WHERE source_code <> '';
+
+UPDATE sessions
+SET revision_id = revisions.id
+FROM revisions
+WHERE revisions.thread_id = sessions.thread_id;
@@ -0,0 +1,7 @@
+-- Forked sessions remember which session they came from, and
+-- which of its revisions they started with.
+ALTER TABLE sessions
+    ADD COLUMN parent_thread_id TEXT
+        REFERENCES sessions (thread_id) ON DELETE SET NULL,
+    ADD COLUMN fork_revision_id BIGINT
+        REFERENCES revisions (id) ON DELETE SET NULL;
@@ -0,0 +1,26 @@
+CREATE TABLE session_members (
+    thread_id TEXT NOT NULL REFERENCES sessions (thread_id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+    PRIMARY KEY (thread_id, user_id)
+);
+
+-- Each session has exactly one owner.
+CREATE UNIQUE INDEX session_members_owner_idx
+    ON session_members (thread_id)
+    WHERE role = 'owner';
+
+-- Whoever started a session owns it, and everyone who was
+-- invited to it can edit it.
+INSERT INTO session_members (thread_id, user_id, role)
+SELECT
+    sessions.thread_id,
+    members.user_id,
+    CASE WHEN members.idx = 1 THEN 'owner' ELSE 'editor' END
+FROM
+    sessions,
+    unnest(sessions.user_ids) WITH ORDINALITY AS members (user_id, idx)
+ORDER BY members.idx
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE sessions DROP COLUMN user_ids;
@@ -0,0 +1,24 @@
+-- Sessions remember the channel their thread is in, when
+-- their code last changed and when they were closed.
+ALTER TABLE sessions
+    ADD COLUMN channel_id TEXT,
+    ADD COLUMN last_active_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN closed_at TIMESTAMPTZ;
+
+UPDATE sessions
+SET
+    last_active_at = latest.created_at
+FROM (
+    SELECT thread_id, MAX(created_at) AS created_at
+    FROM revisions
+    GROUP BY thread_id
+) AS latest
+WHERE
+    latest.thread_id = sessions.thread_id;
+
+CREATE INDEX sessions_channel_id_idx ON sessions (channel_id);
+
+-- Idle sessions are looked up among the open ones.
+CREATE INDEX sessions_last_active_at_idx
+    ON sessions (last_active_at)
+    WHERE closed_at IS NULL;
@@ -0,0 +1,2 @@
+-- Find all sessions that a user is a member of.
+CREATE INDEX session_members_user_id_idx ON session_members (user_id);
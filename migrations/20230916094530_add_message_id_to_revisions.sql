@@ -0,0 +1,4 @@
+-- Revisions remember the message their code was sent in, so
+-- that edits and deletions of the message can be applied to
+-- the code.
+ALTER TABLE revisions ADD COLUMN message_id TEXT;
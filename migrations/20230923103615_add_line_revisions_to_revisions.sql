@@ -0,0 +1,5 @@
+-- For each line of a revision's code, the revision that last
+-- changed it, so that `/blame` doesn't have to go through the
+-- whole history. Without it, all lines count as changed by the
+-- revision itself, like in the first revision of a fork.
+ALTER TABLE revisions ADD COLUMN line_revisions BIGINT[];
@@ -0,0 +1,7 @@
+-- Revisions from a message remember when it was last edited,
+-- so that other updates to the message aren't taken as edits.
+-- Revisions from deleted messages have no author, because it's
+-- not known who deleted them.
+ALTER TABLE revisions
+    ADD COLUMN message_edited_at TIMESTAMPTZ,
+    ALTER COLUMN author_id DROP NOT NULL;
@@ -0,0 +1,67 @@
+/// Evaluate the code read from stdin and write the results
+/// to stdout as JSON. The bot runs this in a separate process
+/// to keep untrusted code away from itself.
+fn main() -> anyhow::Result<()> {
+    limit_resources()?;
+
+    let mut source = String::new();
+    io::stdin()
+        .read_to_string(&mut source)
+        .context("Failed to read code")?;
+
+    // The recursion limit expects a stack this big.
+    let evaluation = thread::Builder::new()
+        .stack_size(EVAL_STACK_SIZE)
+        .spawn(move || UserCode::new(source).eval(EvalBudget::default()))
+        .context("Failed to spawn evaluator thread")?
+        .join()
+        .map_err(|_| anyhow!("Evaluation panicked"))?;
+
+    serde_json::to_writer(io::stdout().lock(), &evaluation)
+        .context("Failed to write evaluation")?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn limit_resources() -> anyhow::Result<()> {
+    let limits = [
+        (libc::RLIMIT_CPU, CPU_LIMIT, "CPU time"),
+        (libc::RLIMIT_AS, ADDRESS_SPACE_LIMIT, "address space"),
+        (libc::RLIMIT_NOFILE, OPEN_FILES_LIMIT, "open files"),
+    ];
+    for (resource, limit, name) in limits {
+        let rlimit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        let rlimit = std::ptr::from_ref(&rlimit);
+        // SAFETY: `rlimit` is valid for the duration of the call.
+        if unsafe { libc::setrlimit(resource, rlimit) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Failed to limit {name}"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn limit_resources() -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Seconds of CPU time the worker may use.
+#[cfg(target_os = "linux")]
+const CPU_LIMIT: libc::rlim_t = 5;
+/// Bytes of memory the worker may map. This includes
+/// the evaluator thread's stack.
+#[cfg(target_os = "linux")]
+const ADDRESS_SPACE_LIMIT: libc::rlim_t = 1024 * 1024 * 1024;
+/// Number of files the worker may have open at once.
+#[cfg(target_os = "linux")]
+const OPEN_FILES_LIMIT: libc::rlim_t = 8;
+
+use std::io::{self, Read};
+use std::thread;
+
+use anyhow::{anyhow, Context};
+use liz::eval::{EvalBudget, UserCode, EVAL_STACK_SIZE};
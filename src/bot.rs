@@ -1,37 +1,198 @@
 pub struct Bot {
-    db:       PgPool,
+    db:        PgPool,
+    evaluator: Evaluator,
+    /// Sessions are closed after being idle for this long.
+    max_idle:  Duration,
+    /// Is the task that closes idle sessions running?
+    expiring:  AtomicBool,
     #[cfg(debug_assertions)]
-    guild_id: GuildId,
+    guild_id:  GuildId,
 }
 
 impl Bot {
     #[cfg(debug_assertions)]
-    pub const fn new(db: PgPool, guild_id: GuildId) -> Self {
-        Self { db, guild_id }
+    pub const fn new(
+        db: PgPool,
+        evaluator: Evaluator,
+        max_idle: Duration,
+        guild_id: GuildId,
+    ) -> Self {
+        Self {
+            db,
+            evaluator,
+            max_idle,
+            expiring: AtomicBool::new(false),
+            guild_id,
+        }
     }
 
     #[cfg(not(debug_assertions))]
-    pub const fn new(db: PgPool) -> Self {
-        Self { db }
+    pub const fn new(
+        db: PgPool,
+        evaluator: Evaluator,
+        max_idle: Duration,
+    ) -> Self {
+        Self {
+            db,
+            evaluator,
+            max_idle,
+            expiring: AtomicBool::new(false),
+        }
+    }
+
+    /// Evaluate the code and return a response message
+    /// including both the code and the result of
+    /// evaluating it.
+    async fn respond(
+        &self,
+        code: &UserCode,
+        truncation: Truncation,
+    ) -> Response {
+        match self.evaluator.eval(code).await {
+            Ok(evaluation) => code.respond(evaluation.as_ref(), truncation),
+            Err(err) => {
+                error!("Failed to evaluate code: {}", err);
+                let mut response = code.respond(None, truncation);
+                response.note = Some(err.to_string());
+                response
+            },
+        }
+    }
+
+    /// Evaluate a session's code and put buttons to work on
+    /// the session under the response.
+    async fn respond_in_session(
+        &self,
+        code: &UserCode,
+        truncation: Truncation,
+    ) -> Reply {
+        Reply::from(self.respond(code, truncation).await)
+            .with_button(BUTTON_RERUN, "Re-run", ButtonStyle::Primary)
+            .with_button(BUTTON_UNDO, "Undo last", ButtonStyle::Secondary)
+            .with_button(BUTTON_DEL, "Delete last line", ButtonStyle::Secondary)
+            .with_button(
+                BUTTON_FULL_OUTPUT,
+                "Show full output",
+                ButtonStyle::Secondary,
+            )
+            .with_button(BUTTON_SHARE, "Share", ButtonStyle::Secondary)
     }
 
+    /// Store a new session that's owned by `user_id`.
     #[tracing::instrument(name = "Store new session", skip(self), err)]
     async fn create_session(
         &self,
         thread_id: ChannelId,
+        channel_id: ChannelId,
         user_id: UserId,
     ) -> Result<(), anyhow::Error> {
+        let mut tx = self.db.begin().await?;
         sqlx::query!(
             r#"
-            INSERT INTO sessions (thread_id, user_ids, source_code)
+            INSERT INTO sessions (thread_id, channel_id, source_code)
             VALUES ($1, $2, $3)
             "#,
             thread_id.to_string(),
-            &vec![user_id.to_string()],
+            channel_id.to_string(),
             String::new(),
         )
-        .execute(&self.db)
+        .execute(&mut *tx)
+        .await?;
+        add_owner(&mut tx, thread_id, user_id).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Store a new session that starts with a copy of another
+    /// session's current code.
+    #[tracing::instrument(name = "Store forked session", skip(self), err)]
+    async fn create_fork(
+        &self,
+        thread_id: ChannelId,
+        channel_id: ChannelId,
+        user_id: UserId,
+        parent_thread_id: ChannelId,
+    ) -> Result<(), anyhow::Error> {
+        let mut tx = self.db.begin().await?;
+        let inserted = sqlx::query!(
+            r#"
+            INSERT INTO sessions (
+                thread_id,
+                channel_id,
+                source_code,
+                parent_thread_id,
+                fork_revision_id
+            )
+            SELECT $1, $3, source_code, thread_id, revision_id
+            FROM sessions
+            WHERE
+                thread_id = $2
+            "#,
+            thread_id.to_string(),
+            parent_thread_id.to_string(),
+            channel_id.to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            return Err(anyhow!("No session with thread ID {parent_thread_id}"));
+        }
+        add_owner(&mut tx, thread_id, user_id).await?;
+
+        // The copied lines keep the revisions that changed them,
+        // so that `/blame` in the fork still shows their authors.
+        let forked = sqlx::query!(
+            r#"
+            SELECT
+                revisions.id,
+                revisions.source_code,
+                revisions.line_revisions
+            FROM sessions
+            JOIN revisions ON revisions.id = sessions.fork_revision_id
+            WHERE
+                sessions.thread_id = $1
+            "#,
+            thread_id.to_string(),
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let line_revisions = forked.map(|fork| {
+            line_revisions(fork.id, &fork.source_code, fork.line_revisions)
+        });
+
+        // The copied code is the fork's first revision.
+        sqlx::query!(
+            r#"
+            WITH revision AS (
+                INSERT INTO revisions (
+                    thread_id,
+                    source_code,
+                    author_id,
+                    line_revisions
+                )
+                SELECT thread_id, source_code, $2, $3::BIGINT[]
+                FROM sessions
+                WHERE
+                    thread_id = $1
+                    AND source_code <> ''
+                RETURNING id
+            )
+            UPDATE sessions
+            SET
+                revision_id = revision.id
+            FROM revision
+            WHERE
+                thread_id = $1
+            "#,
+            thread_id.to_string(),
+            user_id.to_string(),
+            line_revisions.as_deref(),
+        )
+        .execute(&mut *tx)
         .await?;
+
+        tx.commit().await?;
         Ok(())
     }
 
@@ -41,13 +202,21 @@ impl Bot {
         thread_id: ChannelId,
     ) -> Result<UserSession, anyhow::Error> {
         struct UserSessionStrings {
-            user_ids:    Vec<String>,
             source_code: String,
+            sexpr_limit: i32,
+            value_limit: i32,
+            revision_id: Option<i64>,
+            closed:      bool,
         }
         let session = sqlx::query_as!(
             UserSessionStrings,
             r#"
-            SELECT user_ids, source_code
+            SELECT
+                source_code,
+                sexpr_limit,
+                value_limit,
+                revision_id,
+                closed_at IS NOT NULL AS "closed!"
             FROM sessions
             WHERE
                 thread_id = $1
@@ -58,269 +227,2374 @@ impl Bot {
         .await?;
 
         Ok(UserSession::new(
-            session
-                .user_ids
-                .iter()
-                .map(|id| {
-                    id.parse::<u64>()
-                        .map(UserId::from)
-                        .expect("Invalid data in db")
-                })
-                .collect::<Vec<UserId>>(),
+            self.get_members(thread_id).await?,
             session.source_code,
+            Truncation {
+                sexpr: usize::try_from(session.sexpr_limit)
+                    .expect("Invalid data in db"),
+                value: usize::try_from(session.value_limit)
+                    .expect("Invalid data in db"),
+            },
+            session.revision_id,
+            session.closed,
         ))
     }
 
+    /// Store the code as a new revision on top of the current
+    /// one and make it the current revision. The revision
+    /// remembers the message the code came from, if any, and
+    /// which revision last changed each line.
     #[tracing::instrument(name = "Update session code", skip(self), err)]
     async fn update_session_code(
         &self,
         thread_id: ChannelId,
+        author: UserId,
+        message: Option<SourceMessage>,
         code: UserCode,
     ) -> Result<(), anyhow::Error> {
+        let author = match message {
+            Some(SourceMessage { deleted: true, .. }) => None,
+            _ => Some(author),
+        };
+        let mut tx = self.db.begin().await?;
+        // The current revision can't change until the new one
+        // is stored on top of it.
+        let parent = sqlx::query!(
+            r#"
+            SELECT
+                revisions.id AS "id?",
+                revisions.source_code AS "source_code?",
+                revisions.line_revisions
+            FROM sessions
+            LEFT JOIN revisions ON revisions.id = sessions.revision_id
+            WHERE
+                sessions.thread_id = $1
+            FOR UPDATE OF sessions
+            "#,
+            thread_id.to_string(),
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // Lines that are new get the new revision's ID, which
+        // isn't known yet. Revision IDs start at 1.
+        let line_revisions = match (parent.id, parent.source_code) {
+            (Some(id), Some(parent_code)) => {
+                let parent_lines = line_revisions(
+                    id,
+                    &parent_code,
+                    parent.line_revisions,
+                );
+                diff::carry(&parent_code, code.as_ref(), &parent_lines, 0)
+            },
+            _ => vec![0; code.as_ref().lines().count()],
+        };
+
         sqlx::query!(
             r#"
+            WITH revision AS (
+                INSERT INTO revisions (
+                    id,
+                    thread_id,
+                    parent_id,
+                    source_code,
+                    author_id,
+                    message_id,
+                    message_edited_at,
+                    line_revisions
+                )
+                SELECT
+                    new.id,
+                    thread_id,
+                    revision_id,
+                    $2,
+                    $3,
+                    $4,
+                    $5::TEXT::TIMESTAMPTZ,
+                    array_replace($6::BIGINT[], 0, new.id)
+                FROM
+                    sessions,
+                    (SELECT nextval('revisions_id_seq') AS id) AS new
+                WHERE
+                    thread_id = $1
+                RETURNING id
+            )
             UPDATE sessions
             SET
-                source_code = $2
+                source_code = $2,
+                revision_id = revision.id,
+                last_active_at = now()
+            FROM revision
             WHERE
                 thread_id = $1
             "#,
             thread_id.to_string(),
-            code.as_ref()
+            code.as_ref(),
+            author.map(|author| author.to_string()),
+            message.map(|message| message.id.to_string()),
+            message
+                .and_then(|message| message.edited_at)
+                .map(|edited_at| edited_at.to_string()),
+            &line_revisions,
         )
-        .execute(&self.db)
+        .execute(&mut *tx)
         .await?;
+
+        tx.commit().await?;
         Ok(())
     }
 
-    #[tracing::instrument(name = "Update session users", skip(self), err)]
-    async fn update_session_users(
+    /// Get a session's current code and where each of its
+    /// lines came from. There are no lines without code.
+    #[tracing::instrument(name = "Get line origins", skip(self))]
+    async fn get_line_origins(
+        &self,
+        thread_id: ChannelId,
+    ) -> Result<(String, Vec<LineOrigin>), anyhow::Error> {
+        let current = sqlx::query!(
+            r#"
+            SELECT
+                revisions.id,
+                revisions.source_code,
+                revisions.line_revisions
+            FROM sessions
+            JOIN revisions ON revisions.id = sessions.revision_id
+            WHERE
+                sessions.thread_id = $1
+            "#,
+            thread_id.to_string()
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        let Some(current) = current else {
+            return Ok((String::new(), Vec::new()));
+        };
+        let line_revisions = line_revisions(
+            current.id,
+            &current.source_code,
+            current.line_revisions,
+        );
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, author_id, message_id
+            FROM revisions
+            WHERE
+                id = ANY($1)
+                OR id = $2
+            "#,
+            &line_revisions,
+            current.id,
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let origins = rows
+            .into_iter()
+            .map(|row| {
+                let author = match row.author_id {
+                    Some(author) => Some(UserId(author.parse()?)),
+                    None => None,
+                };
+                let message_id = match row.message_id {
+                    Some(message_id) => Some(MessageId(message_id.parse()?)),
+                    None => None,
+                };
+                Ok((row.id, LineOrigin { author, message_id }))
+            })
+            .collect::<Result<HashMap<i64, LineOrigin>, anyhow::Error>>()?;
+
+        // Lines copied into a fork are gone from the history
+        // once the session it was forked from is deleted. They
+        // count as changed by the current revision.
+        let orphaned = origins.get(&current.id).map(|origin| LineOrigin {
+            message_id: None,
+            ..*origin
+        });
+        let lines = line_revisions
+            .iter()
+            .map(|id| {
+                origins.get(id).copied().or(orphaned).ok_or_else(|| {
+                    anyhow!("Revision {id} of a line doesn't exist")
+                })
+            })
+            .collect::<Result<Vec<LineOrigin>, anyhow::Error>>()?;
+        Ok((current.source_code, lines))
+    }
+
+    /// Whether an edit of a message is newer than the edits
+    /// of it that were applied already.
+    #[tracing::instrument(name = "Check message edit", skip(self))]
+    async fn is_new_edit(
+        &self,
+        message_id: MessageId,
+        edited_at: Timestamp,
+    ) -> Result<bool, anyhow::Error> {
+        let applied = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT
+                FROM revisions
+                WHERE
+                    message_id = $1
+                    AND message_edited_at >= $2::TEXT::TIMESTAMPTZ
+            ) AS "applied!"
+            "#,
+            message_id.to_string(),
+            edited_at.to_string(),
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(!applied)
+    }
+
+    /// Get all revisions of a session's code, oldest first.
+    #[tracing::instrument(name = "Get session revisions", skip(self))]
+    async fn get_revisions(
+        &self,
+        thread_id: ChannelId,
+    ) -> Result<Vec<Revision>, anyhow::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                revisions.id,
+                revisions.parent_id,
+                revisions.source_code,
+                revisions.author_id,
+                EXTRACT(EPOCH FROM revisions.created_at)::BIGINT
+                    AS "created_at!",
+                COALESCE(revisions.id = sessions.revision_id, FALSE)
+                    AS "current!"
+            FROM revisions
+            JOIN sessions ON sessions.thread_id = revisions.thread_id
+            WHERE
+                revisions.thread_id = $1
+            ORDER BY revisions.id
+            "#,
+            thread_id.to_string()
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .enumerate()
+            .map(|(idx, row)| Revision {
+                number: idx + 1,
+                id: row.id,
+                parent_id: row.parent_id,
+                source_code: row.source_code,
+                author: row.author_id.map(|author| {
+                    author
+                        .parse::<u64>()
+                        .map(UserId::from)
+                        .expect("Invalid data in db")
+                }),
+                created_at: row.created_at,
+                current: row.current,
+            })
+            .collect())
+    }
+
+    /// Make a revision the current one and restore its code.
+    #[tracing::instrument(name = "Check out session code", skip(self), err)]
+    async fn checkout_session_code(
         &self,
         thread_id: ChannelId,
-        user_ids: Vec<UserId>,
+        revision_id: i64,
     ) -> Result<(), anyhow::Error> {
         sqlx::query!(
             r#"
             UPDATE sessions
             SET
-                user_ids = $2
+                revision_id = revisions.id,
+                source_code = revisions.source_code,
+                last_active_at = now()
+            FROM revisions
+            WHERE
+                sessions.thread_id = $1
+                AND revisions.thread_id = $1
+                AND revisions.id = $2
+            "#,
+            thread_id.to_string(),
+            revision_id,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the code that one of the two sessions was forked
+    /// from, if the other one is its parent.
+    #[tracing::instrument(name = "Get merge base", skip(self), err)]
+    async fn get_merge_base(
+        &self,
+        thread_id: ChannelId,
+        other_thread_id: ChannelId,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let base = sqlx::query_scalar!(
+            r#"
+            SELECT revisions.source_code
+            FROM sessions
+            JOIN revisions ON revisions.id = sessions.fork_revision_id
+            WHERE
+                (sessions.thread_id = $1 AND sessions.parent_thread_id = $2)
+                OR (sessions.thread_id = $2 AND sessions.parent_thread_id = $1)
+            LIMIT 1
+            "#,
+            thread_id.to_string(),
+            other_thread_id.to_string(),
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(base)
+    }
+
+    /// Go back to the parent of the current revision. Return
+    /// its code, or `None` if there's nothing to undo.
+    #[tracing::instrument(name = "Undo session code", skip(self), err)]
+    async fn undo_session_code(
+        &self,
+        thread_id: ChannelId,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let undone = sqlx::query_scalar!(
+            r#"
+            UPDATE sessions
+            SET
+                revision_id = current.parent_id,
+                source_code = COALESCE(parent.source_code, ''),
+                last_active_at = now()
+            FROM revisions AS current
+            LEFT JOIN revisions AS parent ON parent.id = current.parent_id
+            WHERE
+                sessions.thread_id = $1
+                AND current.id = sessions.revision_id
+            RETURNING sessions.source_code
+            "#,
+            thread_id.to_string()
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(undone)
+    }
+
+    /// Go forward to the latest revision made on top of the
+    /// current one. Return its code, or `None` if there's
+    /// nothing to redo.
+    #[tracing::instrument(name = "Redo session code", skip(self), err)]
+    async fn redo_session_code(
+        &self,
+        thread_id: ChannelId,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let redone = sqlx::query_scalar!(
+            r#"
+            UPDATE sessions
+            SET
+                revision_id = child.id,
+                source_code = child.source_code,
+                last_active_at = now()
+            FROM revisions AS child
+            WHERE
+                sessions.thread_id = $1
+                AND child.id = (
+                    SELECT MAX(id)
+                    FROM revisions
+                    WHERE
+                        thread_id = $1
+                        AND parent_id IS NOT DISTINCT FROM sessions.revision_id
+                )
+            RETURNING sessions.source_code
+            "#,
+            thread_id.to_string()
+        )
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(redone)
+    }
+
+    #[tracing::instrument(name = "Get session members", skip(self))]
+    async fn get_members(
+        &self,
+        thread_id: ChannelId,
+    ) -> Result<Vec<(UserId, Role)>, anyhow::Error> {
+        let members = sqlx::query!(
+            r#"
+            SELECT user_id, role
+            FROM session_members
+            WHERE
+                thread_id = $1
+            "#,
+            thread_id.to_string()
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        members
+            .into_iter()
+            .map(|member| {
+                let user_id = member.user_id.parse::<u64>()?;
+                Ok((UserId::from(user_id), member.role.parse::<Role>()?))
+            })
+            .collect()
+    }
+
+    /// Get the sessions a user is a member of, the ones that
+    /// changed last first.
+    #[tracing::instrument(name = "Get user sessions", skip(self))]
+    async fn get_user_sessions(
+        &self,
+        user_id: UserId,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<SessionSummary>, anyhow::Error> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                sessions.thread_id,
+                session_members.role,
+                EXTRACT(EPOCH FROM sessions.last_active_at)::BIGINT
+                    AS "last_active_at!",
+                COALESCE(
+                    cardinality(
+                        string_to_array(NULLIF(sessions.source_code, ''), E'\n')
+                    ),
+                    0
+                ) AS "lines!",
+                sessions.closed_at IS NOT NULL AS "closed!"
+            FROM session_members
+            JOIN sessions ON sessions.thread_id = session_members.thread_id
+            WHERE
+                session_members.user_id = $1
+            ORDER BY sessions.last_active_at DESC, sessions.thread_id
+            LIMIT $2
+            OFFSET $3
+            "#,
+            user_id.to_string(),
+            i64::try_from(limit)?,
+            i64::try_from(offset)?,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(SessionSummary {
+                    thread_id: ChannelId(row.thread_id.parse::<u64>()?),
+                    role: row.role.parse::<Role>()?,
+                    last_active_at: row.last_active_at,
+                    lines: usize::try_from(row.lines)?,
+                    closed: row.closed,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the open sessions a user owns, the ones that
+    /// changed last first.
+    #[tracing::instrument(name = "Get owned sessions", skip(self))]
+    async fn get_owned_sessions(
+        &self,
+        user_id: UserId,
+        limit: usize,
+    ) -> Result<Vec<ChannelId>, anyhow::Error> {
+        let thread_ids = sqlx::query_scalar!(
+            r#"
+            SELECT sessions.thread_id
+            FROM session_members
+            JOIN sessions ON sessions.thread_id = session_members.thread_id
+            WHERE
+                session_members.user_id = $1
+                AND session_members.role = 'owner'
+                AND sessions.closed_at IS NULL
+            ORDER BY sessions.last_active_at DESC, sessions.thread_id
+            LIMIT $2
+            "#,
+            user_id.to_string(),
+            i64::try_from(limit)?,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        thread_ids
+            .into_iter()
+            .map(|thread_id| Ok(ChannelId(thread_id.parse::<u64>()?)))
+            .collect()
+    }
+
+    /// Give members a new role or add new members. The
+    /// owner's role never changes this way.
+    #[tracing::instrument(name = "Set member roles", skip(self), err)]
+    async fn set_member_roles(
+        &self,
+        thread_id: ChannelId,
+        user_ids: &[UserId],
+        role: Role,
+    ) -> Result<(), anyhow::Error> {
+        let user_ids = user_ids
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>();
+        sqlx::query!(
+            r#"
+            INSERT INTO session_members (thread_id, user_id, role)
+            SELECT $1, user_id, $3
+            FROM UNNEST($2::TEXT[]) AS user_id
+            ON CONFLICT (thread_id, user_id) DO UPDATE
+            SET
+                role = EXCLUDED.role
+            WHERE
+                session_members.role <> 'owner'
+            "#,
+            thread_id.to_string(),
+            &user_ids,
+            role.as_str(),
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Remove a member from a session. The owner can't be
+    /// removed.
+    #[tracing::instrument(name = "Delete member", skip(self), err)]
+    async fn delete_member(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM session_members
             WHERE
                 thread_id = $1
+                AND user_id = $2
+                AND role <> 'owner'
             "#,
             thread_id.to_string(),
-            &user_ids
-                .iter()
-                .map(std::string::ToString::to_string)
-                .collect::<Vec<String>>(),
+            user_id.to_string(),
         )
         .execute(&self.db)
         .await?;
         Ok(())
     }
 
-    /// Run any update operation on a session by its thread
-    /// ID. Use `update_user_ids` or
-    /// `update_source_code` if only one of the two is
-    /// supposed to change.
-    #[tracing::instrument(
-        name = "Run an updating operation the a session",
-        skip(self, transform, update),
-        err
-    )]
-    async fn run_session_update<S, U, Fut>(
+    /// Make a member the session's owner. The previous owner
+    /// becomes an editor.
+    #[tracing::instrument(name = "Transfer ownership", skip(self), err)]
+    async fn transfer_ownership(
         &self,
         thread_id: ChannelId,
-        caller: UserId,
-        transform: S,
-        update: U,
-    ) -> Result<String, OpError>
-    where
-        S: FnOnce(&mut UserSession) -> Result<String, anyhow::Error> + Send,
-        U: FnOnce(ChannelId, UserSession) -> Fut + Send,
-        Fut: Future<Output = Result<(), anyhow::Error>> + Send,
-    {
-        if let Ok(mut session) = self.get_session(thread_id).await {
-            if session.user_ids.contains(&caller) {
-                match transform(&mut session) {
-                    Ok(msg) => match update(thread_id, session).await {
-                        Ok(()) => Ok(msg),
-                        Err(e) => {
-                            error!(
-                                "Session update failed {}, callback message {}",
-                                e, msg
-                            );
-                            Err(OpError::Update(e))
-                        },
-                    },
-                    Err(e) => {
-                        error!("Operation failed '{}'", e);
-                        Err(e.into())
+        user_id: UserId,
+    ) -> Result<(), anyhow::Error> {
+        let mut tx = self.db.begin().await?;
+        // There can only be one owner at a time, so the
+        // previous one has to step down first.
+        sqlx::query!(
+            r#"
+            UPDATE session_members
+            SET
+                role = 'editor'
+            WHERE
+                thread_id = $1
+                AND role = 'owner'
+            "#,
+            thread_id.to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        let promoted = sqlx::query!(
+            r#"
+            UPDATE session_members
+            SET
+                role = 'owner'
+            WHERE
+                thread_id = $1
+                AND user_id = $2
+            "#,
+            thread_id.to_string(),
+            user_id.to_string(),
+        )
+        .execute(&mut *tx)
+        .await?;
+        if promoted.rows_affected() != 1 {
+            return Err(anyhow!("{user_id} isn't a member of {thread_id}"));
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Make a session read-only.
+    #[tracing::instrument(name = "Close session", skip(self), err)]
+    async fn close_session(
+        &self,
+        thread_id: ChannelId,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET
+                closed_at = now()
+            WHERE
+                thread_id = $1
+                AND closed_at IS NULL
+            "#,
+            thread_id.to_string(),
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Forget the session in a deleted thread, or all sessions
+    /// in a deleted channel.
+    #[tracing::instrument(name = "Delete sessions", skip(self), err)]
+    async fn delete_sessions(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM sessions
+            WHERE
+                thread_id = $1
+                OR channel_id = $1
+            "#,
+            channel_id.to_string(),
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Update session truncation", skip(self), err)]
+    async fn update_session_truncation(
+        &self,
+        thread_id: ChannelId,
+        truncation: Truncation,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE sessions
+            SET
+                sexpr_limit = $2,
+                value_limit = $3
+            WHERE
+                thread_id = $1
+            "#,
+            thread_id.to_string(),
+            i32::try_from(truncation.sexpr)?,
+            i32::try_from(truncation.value)?,
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Run any update operation on a session by its thread
+    /// ID. The caller needs at least the `needed` role. Use
+    /// `update_source_code` if only the code is supposed to
+    /// change.
+    #[tracing::instrument(
+        name = "Run an updating operation the a session",
+        skip(self, transform, update),
+        err
+    )]
+    async fn run_session_update<S, U, Fut>(
+        &self,
+        thread_id: ChannelId,
+        caller: UserId,
+        needed: Role,
+        transform: S,
+        update: U,
+    ) -> Result<String, OpError>
+    where
+        S: FnOnce(&mut UserSession) -> Result<String, anyhow::Error> + Send,
+        U: FnOnce(ChannelId, UserSession) -> Fut + Send,
+        Fut: Future<Output = Result<(), anyhow::Error>> + Send,
+    {
+        if let Ok(mut session) = self.get_session(thread_id).await {
+            if session.closed && needed > Role::Viewer {
+                Err(OpError::Closed)
+            } else if session.has_role(caller, needed) {
+                match transform(&mut session) {
+                    Ok(msg) => match update(thread_id, session).await {
+                        Ok(()) => Ok(msg),
+                        Err(e) => {
+                            error!(
+                                "Session update failed {}, callback message {}",
+                                e, msg
+                            );
+                            Err(OpError::Update(e))
+                        },
+                    },
+                    Err(e) => {
+                        error!("Operation failed '{}'", e);
+                        Err(e.into())
+                    },
+                }
+            } else {
+                Err(OpError::NotAllowed(needed))
+            }
+        } else {
+            Err(OpError::NotFound(thread_id))
+        }
+    }
+
+    // Wrap `run_session_update` to update the session code.
+    // The session's truncation is returned along with the
+    // message, so that the new code can be evaluated.
+    async fn update_source_code<S>(
+        &self,
+        thread_id: ChannelId,
+        caller: UserId,
+        transform: S,
+    ) -> Result<(String, Truncation), OpError>
+    where
+        S: FnOnce(&mut UserCode) -> Result<String, anyhow::Error> + Send,
+    {
+        self.update_source_code_from(thread_id, caller, None, transform)
+            .await
+    }
+
+    // Like `update_source_code`, but the new revision remembers
+    // the message that the code came from.
+    async fn update_source_code_from<S>(
+        &self,
+        thread_id: ChannelId,
+        caller: UserId,
+        message: Option<SourceMessage>,
+        transform: S,
+    ) -> Result<(String, Truncation), OpError>
+    where
+        S: FnOnce(&mut UserCode) -> Result<String, anyhow::Error> + Send,
+    {
+        let mut truncation = Truncation::default();
+        let msg = self
+            .run_session_update(
+                thread_id,
+                caller,
+                Role::Editor,
+                |session| {
+                    truncation = session.truncation;
+                    transform(&mut session.source_code)
+                },
+                |thread_id, updated_session| {
+                    self.update_session_code(
+                        thread_id,
+                        caller,
+                        message,
+                        updated_session.source_code,
+                    )
+                },
+            )
+            .await?;
+        Ok((msg, truncation))
+    }
+
+    /// Replace the code that came from a message with its
+    /// new content after it was edited. Deleted messages have
+    /// no content, so their code is removed. Then the code is
+    /// evaluated again.
+    async fn sync_message(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        message: SourceMessage,
+        content: &str,
+    ) {
+        let message_id = message.id;
+        if let Some(edited_at) = message.edited_at {
+            match self.is_new_edit(message_id, edited_at).await {
+                Err(err) => {
+                    error!("Failed to check message edit: {}", err);
+                    return;
+                },
+                Ok(false) => return,
+                Ok(true) => {},
+            }
+        }
+
+        let Ok((code, origins)) = self.get_line_origins(thread_id).await
+        else {
+            return;
+        };
+        // Only messages that some of the code is still from
+        // are applied.
+        let Some(author) = origins
+            .iter()
+            .find(|origin| origin.message_id == Some(message_id))
+            .and_then(|origin| origin.author)
+        else {
+            return;
+        };
+        let chunk = origins
+            .iter()
+            .map(|origin| origin.message_id == Some(message_id))
+            .collect::<Vec<bool>>();
+        let mut changed = UserCode::new(code.as_str());
+        if !changed.replace_chunk(&chunk, content)
+            || changed.as_ref() == code
+        {
+            return;
+        }
+
+        let run_op = self.update_source_code_from(
+            thread_id,
+            author,
+            Some(message),
+            |source_code| {
+                if source_code.as_ref() != code {
+                    return Err(anyhow!("The code changed in the meantime"));
+                }
+                *source_code = changed;
+                Ok(source_code.as_ref().to_owned())
+            },
+        );
+
+        let reply: Reply = match run_op.await {
+            Ok((code, truncation)) => self
+                .respond_in_session(&UserCode::new(code), truncation)
+                .await,
+            Err(OpError::Update(_)) => "Sorry, I failed to update your code. \
+                                        Maybe try again."
+                .to_owned()
+                .into(),
+            Err(OpError::NotAllowed(needed)) => {
+                not_allowed(author, needed, "edit here").into()
+            },
+            Err(err) => {
+                info!("Didn't apply changed message {}: {}", message_id, err);
+                return;
+            },
+        };
+        if let Err(e) = reply.send(&ctx.http, thread_id).await {
+            error!("Failed to respond with new code, {}", e);
+        }
+    }
+
+    // NOTE: Thread ID and channel ID may be used
+    // interchangeably.
+
+    /// Execute the `/lisp` command, creating a new session.
+    /// With `fork_of`, the session starts with a copy of that
+    /// session's code instead.
+    async fn cmd_create_session_thread(
+        &self,
+        ctx: &Context,
+        orig_channel: ChannelId,
+        user_id: UserId,
+        fork_of: Option<ChannelId>,
+    ) -> String {
+        // Generate a random two-word name.
+        let name = Generator::with_naming(Name::Plain).next().unwrap();
+
+        let create_thread = orig_channel
+            .create_private_thread(&ctx.http, |thread| thread.name(&name));
+        let thread = match create_thread.await {
+            Err(err) => {
+                error!("Failed to create thread: {}", err);
+                return "Can't start new session :(".to_owned();
+            },
+            Ok(thread) => thread,
+        };
+
+        let rollback_thread_creation = || async move {
+            let delete_thread = ctx.http.delete_channel(thread.id.0);
+            if let Err(err) = delete_thread.await {
+                error!(
+                    "Failed to cleanup failed session creation {}: {}",
+                    thread.id, err
+                );
+            }
+            "Failed to create session :(".to_owned()
+        };
+
+        let send_message = thread.send_message(&ctx.http, |message| {
+            message.content(match fork_of {
+                None => format!(
+                    "Here you go {}, let's code!",
+                    user_id.mention()
+                ),
+                Some(parent) => format!(
+                    "Here you go {}, this is a fork of {}. Use `/show` to see \
+                     the code.",
+                    user_id.mention(),
+                    parent.mention()
+                ),
+            })
+        });
+        if let Err(err) = send_message.await {
+            error!("Failed to send initial message to thread: {}", err);
+            return rollback_thread_creation().await;
+        }
+
+        let store = match fork_of {
+            None => {
+                self.create_session(thread.id, orig_channel, user_id).await
+            },
+            Some(parent) => {
+                self.create_fork(thread.id, orig_channel, user_id, parent)
+                    .await
+            },
+        };
+        match store {
+            Err(err) => {
+                error!("Failed to store session: {}", err);
+                rollback_thread_creation().await
+            },
+            Ok(()) if fork_of.is_some() => {
+                format!("Forked this session into {}", thread.id.mention())
+            },
+            Ok(()) => "Started a new session for you : D".to_owned(),
+        }
+    }
+
+    /// Execute the `/fork` command.
+    async fn cmd_fork(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> String {
+        let Ok(session) = self.get_session(thread_id).await else {
+            return "You can only fork a session from inside it.".to_owned();
+        };
+        if !session.has_role(user_id, Role::Viewer) {
+            return not_allowed(user_id, Role::Viewer, "fork this session");
+        }
+
+        // Threads can't contain threads, so the fork is
+        // created next to the session's thread.
+        let Some(parent_channel) = parent_channel(ctx, thread_id).await else {
+            return "Can't fork this session :(".to_owned();
+        };
+
+        self.cmd_create_session_thread(
+            ctx,
+            parent_channel,
+            user_id,
+            Some(thread_id),
+        )
+        .await
+    }
+
+    /// Execute the `/del` command, then evaluate the code again
+    /// like after any other change.
+    async fn cmd_del_from_session(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+        idx: i64,
+    ) -> Reply {
+        let run_op =
+            self.update_source_code(thread_id, user_id, |source_code| {
+                match source_code.del(idx) {
+                    Some(_) => Ok(source_code.as_ref().to_owned()),
+                    None => Err(anyhow!("Nothing to delete")),
+                }
+            });
+
+        match run_op.await {
+            Ok((code, truncation)) => {
+                self.respond_in_session(&UserCode::new(code), truncation)
+                    .await
+            },
+            Err(op_err) => match op_err {
+                OpError::Callback(err) => err.to_string(),
+                OpError::NotFound(_) => "You can't deleting things outside a \
+                                         session thread."
+                    .to_owned(),
+                OpError::Update(_) => "Failed to execute deletion".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "delete stuff here")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+            }
+            .into(),
+        }
+    }
+
+    /// Execute the `/collab` command.
+    async fn cmd_invite_collaborator(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        user_id: UserId,
+        invitees: Invitees,
+        role: Role,
+    ) -> String {
+        // Only the owner gets to have the guild's members looked
+        // through.
+        let check_owner = self.run_session_update(
+            thread_id,
+            user_id,
+            Role::Owner,
+            |_| Ok(String::new()),
+            |_, _| async { Ok(()) },
+        );
+        if let Err(op_err) = check_owner.await {
+            return invite_failed(user_id, op_err);
+        }
+
+        let invited = match invitees {
+            Invitees::User(invited_id) => vec![invited_id],
+            Invitees::Role(guild_id, role_id) => {
+                match role_members(ctx, guild_id, role_id, MAX_INVITES).await {
+                    Err(err) => {
+                        error!("Failed to get role members: {}", err);
+                        return "Failed to get the members of that role"
+                            .to_owned();
+                    },
+                    Ok(None) => {
+                        return format!(
+                            "I can only look for people with a role among \
+                             the first {} members of this server. Invite \
+                             them one by one instead.",
+                            MAX_MEMBER_PAGES * MEMBERS_PAGE
+                        );
+                    },
+                    Ok(Some(invited)) => invited,
+                }
+            },
+        };
+        // Everyone is added to the thread one by one.
+        if invited.len() > MAX_INVITES {
+            return format!(
+                "I can only invite up to {MAX_INVITES} people at once."
+            );
+        }
+
+        let invite =
+            self.invite_collaborators(ctx, thread_id, user_id, &invited, role);
+        match invite.await {
+            Ok(msg) => msg,
+            Err(op_err) => invite_failed(user_id, op_err),
+        }
+    }
+
+    /// Invite people to a session. Everyone who's invited is
+    /// added to the session's thread as well.
+    async fn invite_collaborators(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        user_id: UserId,
+        invited: &[UserId],
+        role: Role,
+    ) -> Result<String, OpError> {
+        self.run_session_update(
+            thread_id,
+            user_id,
+            Role::Owner,
+            |session| {
+                let changed = invited
+                    .iter()
+                    .filter(|invited_id| {
+                        !session.has_role(**invited_id, Role::Owner)
+                    })
+                    .map(|invited_id| invited_id.mention().to_string())
+                    .collect::<Vec<String>>();
+                match changed.as_slice() {
+                    [] if invited.is_empty() => {
+                        Err(anyhow!("There's nobody to invite."))
+                    },
+                    [] => Err(anyhow!("The owner's role can't change.")),
+                    [invited_id] => Ok(format!(
+                        "{invited_id} is now {} in this session",
+                        role.with_article()
+                    )),
+                    _ => Ok(format!(
+                        "{} are now {}s in this session",
+                        changed.join(", "),
+                        role.as_str()
+                    )),
+                }
+            },
+            |thread_id, session| async move {
+                self.add_members(ctx, thread_id, &session, invited, role)
+                    .await
+            },
+        )
+        .await
+    }
+
+    /// Execute the "Invite to my session" user command, which
+    /// lets the caller choose one of their sessions to invite
+    /// the user to. Return a reply if there's no choice.
+    async fn cmd_open_invite_menu(
+        &self,
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+    ) -> Option<Reply> {
+        let user_id = command.user.id;
+        let Some(ResolvedTarget::User(invited, _)) = command.data.target()
+        else {
+            return Some(INVALID_REQUEST_MSG.to_owned().into());
+        };
+        if invited.bot {
+            return Some(
+                Reply::from("Bots can't join sessions.".to_owned()).ephemeral(),
+            );
+        }
+        if invited.id == user_id {
+            return Some(
+                Reply::from("You're already part of your sessions.".to_owned())
+                    .ephemeral(),
+            );
+        }
+
+        let owned = self.get_owned_sessions(user_id, MENU_LIMIT);
+        let thread_ids = match owned.await {
+            Err(err) => {
+                error!("Failed to get owned sessions: {}", err);
+                return Some(
+                    Reply::from("Failed to get your sessions".to_owned())
+                        .ephemeral(),
+                );
+            },
+            Ok(thread_ids) if thread_ids.is_empty() => {
+                return Some(
+                    Reply::from(format!(
+                        "You don't own any open sessions. Use \
+                         `/{CMD_SESSION}` to start one."
+                    ))
+                    .ephemeral(),
+                );
+            },
+            Ok(thread_ids) => thread_ids,
+        };
+        // Thread names can't be mentioned in menus, so they're
+        // looked up.
+        let mut sessions = Vec::new();
+        for thread_id in thread_ids {
+            let name = match thread_id.to_channel(ctx).await {
+                Ok(Channel::Guild(thread)) => thread.name,
+                _ => thread_id.to_string(),
+            };
+            sessions.push((thread_id, name));
+        }
+
+        // The menu replaces the deferred response.
+        let open_menu =
+            command.edit_original_interaction_response(&ctx.http, |message| {
+                message
+                    .content(format!(
+                        "Which session do you want to invite {} to?",
+                        invited.id.mention()
+                    ))
+                    .components(|c| {
+                        c.create_action_row(|row| {
+                            row.create_select_menu(|menu| {
+                                menu.custom_id(format!(
+                                    "{SELECT_INVITE}:{}",
+                                    invited.id
+                                ))
+                                .placeholder("Choose a session")
+                                .options(|options| {
+                                    for (thread_id, name) in &sessions {
+                                        options.create_option(|option| {
+                                            option.label(name).value(thread_id)
+                                        });
+                                    }
+                                    options
+                                })
+                            })
+                        })
+                    })
+            });
+        if let Err(err) = open_menu.await {
+            error!("Failed to open invite menu: {}", err);
+            return Some(
+                Reply::from("Failed to list your sessions :(".to_owned())
+                    .ephemeral(),
+            );
+        }
+        None
+    }
+
+    /// Invite the user from the "Invite to my session" command
+    /// to the session that was chosen in the menu.
+    async fn invite_from_menu(
+        &self,
+        ctx: &Context,
+        component: &MessageComponentInteraction,
+        invited: &str,
+    ) -> Reply {
+        let user_id = component.user.id;
+        let thread_id = component
+            .data
+            .values
+            .first()
+            .and_then(|thread_id| thread_id.parse::<u64>().ok());
+        let (Ok(invited_id), Some(thread_id)) =
+            (invited.parse::<u64>(), thread_id)
+        else {
+            return INVALID_REQUEST_MSG.to_owned().into();
+        };
+        let (invited_id, thread_id) =
+            (UserId(invited_id), ChannelId(thread_id));
+
+        let invited = [invited_id];
+        let invite = self.invite_collaborators(
+            ctx,
+            thread_id,
+            user_id,
+            &invited,
+            Role::Editor,
+        );
+        match invite.await {
+            Ok(msg) => {
+                // Everyone in the session sees who joined.
+                if let Err(err) = thread_id.say(&ctx.http, msg).await {
+                    error!("Failed to announce invite: {}", err);
+                }
+                format!(
+                    "Invited {} to {}",
+                    invited_id.mention(),
+                    thread_id.mention()
+                )
+                .into()
+            },
+            Err(op_err) => invite_failed(user_id, op_err).into(),
+        }
+    }
+
+    /// Add people to a session's thread and give them a role.
+    /// If anything fails, the people who weren't members
+    /// before are removed from the thread again.
+    async fn add_members(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        session: &UserSession,
+        user_ids: &[UserId],
+        role: Role,
+    ) -> Result<(), anyhow::Error> {
+        let mut added = Vec::new();
+        let mut result = Ok(());
+        for user_id in user_ids {
+            let add_member = thread_id.add_thread_member(&ctx.http, *user_id);
+            if let Err(err) = add_member.await {
+                result = Err(err.into());
+                break;
+            }
+            if session.role_of(*user_id).is_none() {
+                added.push(*user_id);
+            }
+        }
+        if result.is_ok() {
+            result = self.set_member_roles(thread_id, user_ids, role).await;
+        }
+
+        if result.is_err() {
+            for user_id in added {
+                let remove_member =
+                    thread_id.remove_thread_member(&ctx.http, user_id);
+                if let Err(err) = remove_member.await {
+                    error!(
+                        "Failed to cleanup failed invite of {} to {}: {}",
+                        user_id, thread_id, err
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    /// Remove someone from a session and its thread. If they
+    /// can't be removed from the session, they're added back
+    /// to the thread.
+    async fn remove_member(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> Result<(), anyhow::Error> {
+        thread_id.remove_thread_member(&ctx.http, user_id).await?;
+        let delete = self.delete_member(thread_id, user_id).await;
+        if delete.is_err() {
+            let add_member = thread_id.add_thread_member(&ctx.http, user_id);
+            if let Err(err) = add_member.await {
+                error!(
+                    "Failed to cleanup failed removal of {} from {}: {}",
+                    user_id, thread_id, err
+                );
+            }
+        }
+        delete
+    }
+
+    /// Execute the `/kick` command.
+    async fn cmd_kick(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        user_id: UserId,
+        kicked_id: UserId,
+    ) -> String {
+        let run_op = self.run_session_update(
+            thread_id,
+            user_id,
+            Role::Owner,
+            |session| match session.role_of(kicked_id) {
+                None => Err(anyhow!(
+                    "{} isn't part of this session.",
+                    kicked_id.mention()
+                )),
+                Some(Role::Owner) => Err(anyhow!(
+                    "The owner can't be kicked. Use `/{CMD_TRANSFER}` to hand \
+                     the session over first."
+                )),
+                Some(_) => Ok(format!(
+                    "{} removed {} from this session",
+                    user_id.mention(),
+                    kicked_id.mention()
+                )),
+            },
+            |thread_id, _| self.remove_member(ctx, thread_id, kicked_id),
+        );
+
+        match run_op.await {
+            Ok(msg) => msg,
+            Err(op_err) => match op_err {
+                OpError::Callback(err) => err.to_string(),
+                OpError::NotFound(_) => {
+                    "You can only kick people from inside a session.".to_owned()
+                },
+                OpError::Update(_) => "Failed to kick them :(".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "kick people")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+            },
+        }
+    }
+
+    /// Execute the `/leave` command.
+    async fn cmd_leave(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
+        user_id: UserId,
+    ) -> String {
+        let run_op = self.run_session_update(
+            thread_id,
+            user_id,
+            Role::Viewer,
+            |session| {
+                if session.has_role(user_id, Role::Owner) {
+                    return Err(anyhow!(
+                        "The owner can't leave. Use `/{CMD_TRANSFER}` to hand \
+                         the session over first."
+                    ));
+                }
+                Ok(format!("{} left this session", user_id.mention()))
+            },
+            |thread_id, _| self.remove_member(ctx, thread_id, user_id),
+        );
+
+        match run_op.await {
+            Ok(msg) => msg,
+            Err(op_err) => match op_err {
+                OpError::Callback(err) => err.to_string(),
+                OpError::NotFound(_) => {
+                    "You can only leave a session from inside it.".to_owned()
+                },
+                OpError::Update(_) => "Failed to leave :(".to_owned(),
+                OpError::NotAllowed(_) => "You're not part of this session."
+                    .to_owned(),
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+            },
+        }
+    }
+
+    /// Execute the `/transfer` command. If the owner left the
+    /// server, editors may transfer the session as well.
+    async fn cmd_transfer(
+        &self,
+        ctx: &Context,
+        guild_id: Option<GuildId>,
+        thread_id: ChannelId,
+        user_id: UserId,
+        new_owner_id: UserId,
+    ) -> String {
+        let owner = match self.get_session(thread_id).await {
+            Ok(session) => session.owner(),
+            Err(_) => None,
+        };
+        let needed = match (owner, guild_id) {
+            (Some(owner), Some(guild_id))
+                if owner != user_id && has_left(ctx, guild_id, owner).await =>
+            {
+                Role::Editor
+            },
+            _ => Role::Owner,
+        };
+
+        let run_op = self.run_session_update(
+            thread_id,
+            user_id,
+            needed,
+            |session| match session.role_of(new_owner_id) {
+                None => Err(anyhow!(
+                    "You can only hand the session to one of its members."
+                )),
+                Some(Role::Owner) => {
+                    Err(anyhow!("They already own this session."))
+                },
+                Some(_) => Ok(format!(
+                    "{} now owns this session",
+                    new_owner_id.mention()
+                )),
+            },
+            |thread_id, _| self.transfer_ownership(thread_id, new_owner_id),
+        );
+
+        match run_op.await {
+            Ok(msg) => msg,
+            Err(op_err) => match op_err {
+                OpError::Callback(err) => err.to_string(),
+                OpError::NotFound(_) => "You can only transfer a session from \
+                                         inside it."
+                    .to_owned(),
+                OpError::Update(_) => "Failed to transfer session".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "transfer this session")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+            },
+        }
+    }
+
+    /// Execute the `/close` command. The thread is archived
+    /// once the response was sent.
+    async fn cmd_close(&self, thread_id: ChannelId, user_id: UserId) -> String {
+        let run_op = self.run_session_update(
+            thread_id,
+            user_id,
+            Role::Owner,
+            |_| {
+                Ok(format!(
+                    "{} closed this session. Its code can't change anymore, \
+                     but you can still look at it or `/{CMD_FORK}` it.",
+                    user_id.mention()
+                ))
+            },
+            |thread_id, _| self.close_session(thread_id),
+        );
+
+        match run_op.await {
+            Ok(msg) => msg,
+            Err(op_err) => match op_err {
+                OpError::Callback(_) => INVALID_REQUEST_MSG.to_owned(),
+                OpError::NotFound(_) => {
+                    "You can only close a session from inside it.".to_owned()
+                },
+                OpError::Update(_) => "Failed to close session".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "close this session")
+                },
+                OpError::Closed => "This session is already closed.".to_owned(),
+            },
+        }
+    }
+
+    /// Execute the `/sessions` command. Sessions are listed
+    /// in pages, and the page's buttons show the others.
+    async fn cmd_sessions(&self, user_id: UserId, page: usize) -> Reply {
+        // Get one more session to know if there's another page.
+        let offset = page * SESSIONS_PAGE;
+        let get_sessions =
+            self.get_user_sessions(user_id, SESSIONS_PAGE + 1, offset);
+        let mut sessions = match get_sessions.await {
+            Err(err) => {
+                error!("Failed to get sessions: {}", err);
+                return Reply::from("Failed to get your sessions".to_owned())
+                    .ephemeral();
+            },
+            Ok(sessions) => sessions,
+        };
+        let has_next = sessions.len() > SESSIONS_PAGE;
+        sessions.truncate(SESSIONS_PAGE);
+        if sessions.is_empty() && page == 0 {
+            return Reply::from(format!(
+                "You're not part of any session yet. Use `/{CMD_SESSION}` to \
+                 start one."
+            ))
+            .ephemeral();
+        }
+
+        let mut list = format!("Your sessions, page {}:", page + 1);
+        for session in sessions {
+            let _ = write!(
+                list,
+                "\n{} · {} · {} line{} · changed <t:{}:R>{}",
+                session.thread_id.mention(),
+                session.role.as_str(),
+                session.lines,
+                if session.lines == 1 { "" } else { "s" },
+                session.last_active_at,
+                if session.closed { " · closed" } else { "" }
+            );
+        }
+
+        let mut reply = Reply::from(list).ephemeral();
+        if page > 0 {
+            reply = reply.with_button(
+                format!("{BUTTON_SESSIONS}:{}", page - 1),
+                "Previous",
+                ButtonStyle::Secondary,
+            );
+        }
+        if has_next {
+            reply = reply.with_button(
+                format!("{BUTTON_SESSIONS}:{}", page + 1),
+                "Next",
+                ButtonStyle::Secondary,
+            );
+        }
+        reply
+    }
+
+    /// Execute the `/show` command.
+    async fn cmd_show(&self, thread_id: ChannelId) -> String {
+        match self.get_session(thread_id).await {
+            Err(_) => "There's no code to show outside a session.".to_owned(),
+            Ok(session) if session.source_code.as_ref().is_empty() => {
+                "There's no code in this session yet.".to_owned()
+            },
+            Ok(session) => {
+                format!("```\n{}\n```", session.source_code.numbered())
+            },
+        }
+    }
+
+    /// Execute the `/insert` and `/replace` commands, which
+    /// put code at a line and evaluate the result.
+    async fn cmd_edit_line(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+        edit: LineEdit<'_>,
+    ) -> Reply {
+        let run_op =
+            self.update_source_code(thread_id, user_id, |source_code| {
+                match edit {
+                    LineEdit::Insert(line, code) => {
+                        source_code.insert(line, code)?;
+                    },
+                    LineEdit::Replace(line, code) => {
+                        source_code.replace(line, code)?;
+                    },
+                }
+                Ok(source_code.as_ref().to_owned())
+            });
+
+        match run_op.await {
+            Ok((code, truncation)) => self
+                .respond_in_session(&UserCode::new(code), truncation)
+                .await,
+            Err(op_err) => match op_err {
+                OpError::Callback(err) => err.to_string(),
+                OpError::NotFound(_) => {
+                    "You can only edit code inside a session.".to_owned()
+                },
+                OpError::Update(_) => "Failed to update the code".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "edit here")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+            }
+            .into(),
+        }
+    }
+
+    /// Execute the `/edit` command by opening an editor with
+    /// the session's code, or only some of its lines. Return
+    /// a reply if the editor can't be opened.
+    async fn cmd_open_editor(
+        &self,
+        ctx: &Context,
+        command: &ApplicationCommandInteraction,
+    ) -> Option<Reply> {
+        let user_id = command.user.id;
+        let Ok(session) = self.get_session(command.channel_id).await else {
+            return Some(
+                "You can only edit code inside a session.".to_owned().into(),
+            );
+        };
+        if session.closed {
+            return Some(CLOSED_SESSION_MSG.to_owned().into());
+        }
+        if !session.has_role(user_id, Role::Editor) {
+            return Some(not_allowed(user_id, Role::Editor, "edit here").into());
+        }
+
+        let line = |name: &str| match option_value(command, name)? {
+            CommandDataOptionValue::Integer(line) => {
+                usize::try_from(*line).ok()
+            },
+            _ => None,
+        };
+        let code = &session.source_code;
+        let revision = session.revision_id.unwrap_or_default();
+        let (text, custom_id, title) =
+            match (line(CMD_EDIT_FROM), line(CMD_EDIT_TO)) {
+                (None, None) => (
+                    code.as_ref().to_owned(),
+                    format!("{MODAL_EDIT}:{revision}"),
+                    "Edit the code".to_owned(),
+                ),
+                (from, to) => {
+                    let from = from.unwrap_or(1);
+                    let to =
+                        to.unwrap_or_else(|| code.as_ref().lines().count());
+                    match code.lines(from, to) {
+                        Err(err) => return Some(err.to_string().into()),
+                        Ok(text) => (
+                            text,
+                            format!("{MODAL_EDIT}:{revision}:{from}:{to}"),
+                            format!("Edit lines {from} to {to}"),
+                        ),
+                    }
+                },
+            };
+        if text.chars().count() > EDITOR_LIMIT {
+            return Some(
+                format!(
+                    "This is too long for the editor. Choose fewer lines \
+                     with `{CMD_EDIT_FROM}` and `{CMD_EDIT_TO}`."
+                )
+                .into(),
+            );
+        }
+
+        let open_editor = command.create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::Modal)
+                .interaction_response_data(|modal| {
+                    modal.custom_id(custom_id).title(title).components(|c| {
+                        c.create_action_row(|row| {
+                            row.create_input_text(|input| {
+                                if !text.is_empty() {
+                                    input.value(&text);
+                                }
+                                input
+                                    .custom_id(EDITOR_INPUT)
+                                    .label("Code")
+                                    .style(InputTextStyle::Paragraph)
+                                    .max_length(EDITOR_LIMIT as u64)
+                                    .required(false)
+                            })
+                        })
+                    })
+                })
+        });
+        if let Err(err) = open_editor.await {
+            error!("Failed to open editor: {}", err);
+            return Some("Failed to open the editor :(".to_owned().into());
+        }
+        None
+    }
+
+    /// Save the code from the editor that `/edit` opened. It's
+    /// only saved if the code didn't change in the meantime.
+    async fn submit_editor(
+        &self,
+        modal: &ModalSubmitInteraction,
+        args: &str,
+    ) -> Reply {
+        let thread_id = modal.channel_id;
+        let user_id = modal.user.id;
+        let args = args
+            .split(':')
+            .map(str::parse::<i64>)
+            .collect::<Result<Vec<i64>, _>>();
+        let (revision, range) = match args.as_deref() {
+            Ok([revision]) => (*revision, None),
+            Ok([revision, from, to]) => {
+                let from = usize::try_from(*from).unwrap_or_default();
+                let to = usize::try_from(*to).unwrap_or_default();
+                (*revision, Some((from, to)))
+            },
+            _ => return INVALID_REQUEST_MSG.to_owned().into(),
+        };
+        let text = modal
+            .data
+            .components
+            .iter()
+            .flat_map(|row| &row.components)
+            .find_map(|component| match component {
+                ActionRowComponent::InputText(input)
+                    if input.custom_id == EDITOR_INPUT =>
+                {
+                    Some(input.value.as_str())
+                },
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        let current = match self.get_session(thread_id).await {
+            Ok(session) => session.revision_id.unwrap_or_default(),
+            Err(_) => {
+                return "This session doesn't exist anymore.".to_owned().into();
+            },
+        };
+        if current != revision {
+            return Reply::from(
+                "The code changed while you were editing it, so I didn't \
+                 save your changes. Here they are, so they're not lost."
+                    .to_owned(),
+            )
+            .with_code("lisp", text, "edit.lisp")
+            .ephemeral();
+        }
+
+        let run_op =
+            self.update_source_code(thread_id, user_id, |source_code| {
+                match range {
+                    None => *source_code = UserCode::new(text),
+                    Some((from, to)) => {
+                        source_code.replace_lines(from, to, text)?;
                     },
                 }
-            } else {
-                Err(OpError::NotAllowed)
+                Ok(source_code.as_ref().to_owned())
+            });
+
+        match run_op.await {
+            Ok((code, truncation)) => {
+                self.respond_in_session(&UserCode::new(code), truncation)
+                    .await
+            },
+            Err(op_err) => match op_err {
+                OpError::Callback(err) => err.to_string(),
+                OpError::NotFound(_) => {
+                    "You can only edit code inside a session.".to_owned()
+                },
+                OpError::Update(_) => "Failed to update the code".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "edit here")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
             }
-        } else {
-            Err(OpError::NotFound(thread_id))
+            .into(),
         }
     }
 
-    /// Wrap `run_session_update` to update the user IDs.
-    async fn update_user_ids<S>(
+    /// Execute the `/undo` and `/redo` commands, which move
+    /// through the revisions of a session's code.
+    async fn cmd_step_history(
         &self,
         thread_id: ChannelId,
-        caller: UserId,
-        transform: S,
-    ) -> Result<String, OpError>
-    where
-        S: FnOnce(&mut Vec<UserId>) -> Result<String, anyhow::Error> + Send,
-    {
-        self.run_session_update(
-            thread_id,
-            caller,
-            |session| transform(&mut session.user_ids),
-            |thread_id, updated_session| {
-                self.update_session_users(thread_id, updated_session.user_ids)
+        user_id: UserId,
+        step: HistoryStep,
+    ) -> Reply {
+        let Ok(session) = self.get_session(thread_id).await else {
+            return "There's no history outside a session."
+                .to_owned()
+                .into();
+        };
+        if session.closed {
+            return CLOSED_SESSION_MSG.to_owned().into();
+        }
+        if !session.has_role(user_id, Role::Editor) {
+            return not_allowed(user_id, Role::Editor, "edit here").into();
+        }
+
+        let stepped = match step {
+            HistoryStep::Undo => self.undo_session_code(thread_id).await,
+            HistoryStep::Redo => self.redo_session_code(thread_id).await,
+        };
+        match stepped {
+            Ok(Some(code)) => self
+                .respond_in_session(&UserCode::new(code), session.truncation)
+                .await,
+            Ok(None) => match step {
+                HistoryStep::Undo => "There's nothing to undo.",
+                HistoryStep::Redo => "There's nothing to redo.",
+            }
+            .to_owned()
+            .into(),
+            Err(_) => "Failed to update the code".to_owned().into(),
+        }
+    }
+
+    /// Execute the `/history` command.
+    async fn cmd_history(&self, thread_id: ChannelId) -> String {
+        let revisions = match self.get_revisions(thread_id).await {
+            Err(err) => {
+                error!("Failed to get revisions: {}", err);
+                return "Failed to get the history of this session".to_owned();
             },
-        )
-        .await
+            Ok(revisions) if revisions.is_empty() => {
+                return "There's no history here yet.".to_owned();
+            },
+            Ok(revisions) => revisions,
+        };
+
+        let mut history = revisions
+            .iter()
+            .rev()
+            .take(HISTORY_LEN)
+            .map(|revision| {
+                let parent = revision.parent_id.and_then(|parent_id| {
+                    revisions.iter().find(|other| other.id == parent_id)
+                });
+                let parent_code =
+                    parent.map_or("", |parent| parent.source_code.as_str());
+                let author = revision.author.map_or_else(
+                    || "a deleted message".to_owned(),
+                    |author| author.mention().to_string(),
+                );
+                format!(
+                    "`#{}` <t:{}:R> by {}: {}{}",
+                    revision.number,
+                    revision.created_at,
+                    author,
+                    summarize(parent_code, &revision.source_code),
+                    if revision.current { " (current)" } else { "" }
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        if revisions.len() > HISTORY_LEN {
+            let _ = write!(
+                history,
+                "\n...and {} older revisions",
+                revisions.len() - HISTORY_LEN
+            );
+        }
+        history
     }
 
-    // Wrap `run_session_update` to update the session code.
-    async fn update_source_code<S>(
+    /// Execute the `/blame` command, which shows who wrote
+    /// each line of the current code.
+    async fn cmd_blame(&self, ctx: &Context, thread_id: ChannelId) -> String {
+        let (code, origins) = match self.get_line_origins(thread_id).await {
+            Err(err) => {
+                error!("Failed to get line origins: {}", err);
+                return "Failed to get the history of this session".to_owned();
+            },
+            Ok(lines) => lines,
+        };
+        let authors = origins
+            .iter()
+            .map(|origin| origin.author)
+            .collect::<Vec<Option<UserId>>>();
+        if code.is_empty() {
+            return "There's no code in this session yet.".to_owned();
+        }
+
+        // Label authors with their initials in the gutter.
+        let mut labels: Vec<(UserId, String)> = Vec::new();
+        for author in authors.iter().flatten() {
+            if labels.iter().any(|(user_id, _)| user_id == author) {
+                continue;
+            }
+            let name = match author.to_user(ctx).await {
+                Ok(user) => user.name,
+                Err(_) => "?".to_owned(),
+            };
+            let mut label = initials(&name);
+            // Number authors with the same initials.
+            let same = labels
+                .iter()
+                .filter(|(_, other)| other.starts_with(&label))
+                .count();
+            if same > 0 {
+                label.push_str(&(same + 1).to_string());
+            }
+            labels.push((*author, label));
+        }
+        let label_of = |author: &Option<UserId>| {
+            labels
+                .iter()
+                .find(|(user_id, _)| Some(*user_id) == *author)
+                .map_or("", |(_, label)| label.as_str())
+        };
+
+        let width = labels
+            .iter()
+            .map(|(_, label)| label.chars().count())
+            .max()
+            .unwrap_or_default();
+        let numbers = code.lines().count().to_string().len();
+        let gutter = code
+            .lines()
+            .zip(&authors)
+            .enumerate()
+            .map(|(idx, (line, author))| {
+                format!(
+                    "{:<width$} {:>numbers$} | {line}",
+                    label_of(author),
+                    idx + 1
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        let legend = labels
+            .iter()
+            .map(|(user_id, label)| format!("`{label}` {}", user_id.mention()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!("```\n{gutter}\n```\n{legend}")
+    }
+
+    /// Execute the `/checkout` command.
+    async fn cmd_checkout(
         &self,
         thread_id: ChannelId,
-        caller: UserId,
-        transform: S,
-    ) -> Result<String, OpError>
-    where
-        S: FnOnce(&mut UserCode) -> Result<String, anyhow::Error> + Send,
-    {
-        self.run_session_update(
-            thread_id,
-            caller,
-            |session| transform(&mut session.source_code),
-            |thread_id, updated_session| {
-                self.update_session_code(thread_id, updated_session.source_code)
+        user_id: UserId,
+        number: usize,
+    ) -> Reply {
+        let Ok(session) = self.get_session(thread_id).await else {
+            return "There's no history outside a session."
+                .to_owned()
+                .into();
+        };
+        if session.closed {
+            return CLOSED_SESSION_MSG.to_owned().into();
+        }
+        if !session.has_role(user_id, Role::Editor) {
+            return not_allowed(user_id, Role::Editor, "edit here").into();
+        }
+
+        let revision = match self.get_revisions(thread_id).await {
+            Err(_) => return "Failed to get the history".to_owned().into(),
+            Ok(mut revisions) if (1..=revisions.len()).contains(&number) => {
+                revisions.swap_remove(number - 1)
             },
+            Ok(_) => return format!("There's no revision #{number}").into(),
+        };
+        if let Err(err) =
+            self.checkout_session_code(thread_id, revision.id).await
+        {
+            error!("Failed to check out revision: {}", err);
+            return "Failed to check out the revision".to_owned().into();
+        }
+
+        self.respond_in_session(
+            &UserCode::new(revision.source_code),
+            session.truncation,
         )
         .await
     }
 
-    // NOTE: Thread ID and channel ID may be used
-    // interchangeably.
+    /// Execute the `/diff` command.
+    async fn cmd_diff(
+        &self,
+        thread_id: ChannelId,
+        old: usize,
+        new: usize,
+    ) -> Reply {
+        let revisions = match self.get_revisions(thread_id).await {
+            Err(_) => return "Failed to get the history".to_owned().into(),
+            Ok(revisions) => revisions,
+        };
+        let get_code = |number: usize| {
+            number
+                .checked_sub(1)
+                .and_then(|idx| revisions.get(idx))
+                .map(|revision| revision.source_code.as_str())
+        };
+        let (Some(old_code), Some(new_code)) = (get_code(old), get_code(new))
+        else {
+            return format!(
+                "This session has revisions #1 to #{}",
+                revisions.len()
+            )
+            .into();
+        };
 
-    /// Execute the `/lisp` command, creating a new session.
-    async fn cmd_create_session_thread(
+        let diff = diff::unified(old_code, new_code, DIFF_CONTEXT);
+        if diff.is_empty() {
+            return format!("Revisions #{old} and #{new} are the same.").into();
+        }
+        Reply::from(format!("Changes from revision #{old} to #{new}:"))
+            .with_code(
+                "diff",
+                &format!("--- #{old}\n+++ #{new}\n{}", diff.trim_end()),
+                "revisions.diff",
+            )
+    }
+
+    /// Merge the code of the session in `source` into the
+    /// session in `thread_id`. Return `None` if neither was
+    /// forked from the other, because then there's no common
+    /// code to compare their changes to.
+    async fn merge_sessions(
         &self,
         ctx: &Context,
-        orig_channel: ChannelId,
+        (thread_id, ours): (ChannelId, &UserSession),
+        (source, theirs): (ChannelId, &UserSession),
+    ) -> Result<Option<diff::Merge>, anyhow::Error> {
+        let Some(base) = self.get_merge_base(thread_id, source).await? else {
+            return Ok(None);
+        };
+        let name = match source.to_channel(ctx).await?.guild() {
+            Some(thread) => thread.name,
+            None => source.to_string(),
+        };
+        Ok(Some(diff::merge(
+            &base,
+            ours.source_code.as_ref(),
+            theirs.source_code.as_ref(),
+            ["this session", &name],
+        )))
+    }
+
+    /// Execute the `/merge` command. The merge is only shown
+    /// until the session's owner confirms it.
+    async fn cmd_merge(
+        &self,
+        ctx: &Context,
+        thread_id: ChannelId,
         user_id: UserId,
-    ) -> String {
-        // Generate a random two-word name.
-        let name = Generator::with_naming(Name::Plain).next().unwrap();
+        source: ChannelId,
+    ) -> Reply {
+        if source == thread_id {
+            return "You can't merge a session into itself."
+                .to_owned()
+                .into();
+        }
+        let Ok(ours) = self.get_session(thread_id).await else {
+            return "You can only merge into a session from inside it."
+                .to_owned()
+                .into();
+        };
+        let Ok(theirs) = self.get_session(source).await else {
+            return format!("{} isn't a session.", source.mention()).into();
+        };
+        if ours.closed {
+            return CLOSED_SESSION_MSG.to_owned().into();
+        }
+        if !ours.has_role(user_id, Role::Editor) {
+            return not_allowed(user_id, Role::Editor, "merge into this session")
+                .into();
+        }
+        if !theirs.has_role(user_id, Role::Viewer) {
+            return not_allowed(user_id, Role::Viewer, "merge from there")
+                .into();
+        }
 
-        let create_thread = orig_channel
-            .create_private_thread(&ctx.http, |thread| thread.name(&name));
-        let thread = match create_thread.await {
+        let merge = match self
+            .merge_sessions(ctx, (thread_id, &ours), (source, &theirs))
+            .await
+        {
             Err(err) => {
-                error!("Failed to create thread: {}", err);
-                return "Can't start new session :(".to_owned();
+                error!("Failed to merge sessions: {}", err);
+                return "Failed to merge the code".to_owned().into();
             },
-            Ok(thread) => thread,
+            Ok(None) => return unrelated_sessions(source).into(),
+            Ok(Some(merge)) => merge,
         };
+        if merge.text == ours.source_code.as_ref() {
+            return format!(
+                "There's nothing to merge from {}.",
+                source.mention()
+            )
+            .into();
+        }
 
-        let rollback_thread_creation = || async move {
-            let delete_thread = ctx.http.delete_channel(thread.id.0);
-            if let Err(err) = delete_thread.await {
-                error!(
-                    "Failed to cleanup failed session creation {}: {}",
-                    thread.id, err
-                );
-            }
-            "Failed to create session :(".to_owned()
+        let conflicts = match merge.conflicts {
+            0 => String::new(),
+            1 => " There is 1 conflict.".to_owned(),
+            n => format!(" There are {n} conflicts."),
         };
+        let owner = ours.owner().map_or_else(
+            || "the owner".to_owned(),
+            |owner| owner.mention().to_string(),
+        );
+        let diff =
+            diff::unified(ours.source_code.as_ref(), &merge.text, DIFF_CONTEXT);
+        let confirm_id = format!(
+            "{BUTTON_MERGE}:{source}:{}:{}",
+            ours.revision_id.unwrap_or_default(),
+            theirs.revision_id.unwrap_or_default()
+        );
+        Reply::from(format!(
+            "Merging {} into this session.{conflicts} Have a look at the \
+             changes, {owner} can confirm them.",
+            source.mention(),
+        ))
+        .with_code("diff", diff.trim_end(), "merge.diff")
+        .with_button(confirm_id, "Merge", ButtonStyle::Success)
+        .with_button(BUTTON_MERGE_CANCEL, "Cancel", ButtonStyle::Secondary)
+    }
 
-        let send_message = thread.send_message(&ctx.http, |message| {
-            message.content(format!(
-                "Here you go {}, let's code!",
-                user_id.mention()
+    /// Apply a merge after the owner clicked its button. The
+    /// merge is redone, unless either session changed since.
+    async fn confirm_merge(
+        &self,
+        ctx: &Context,
+        component: &MessageComponentInteraction,
+        args: &str,
+    ) -> Reply {
+        let thread_id = component.channel_id;
+        let user_id = component.user.id;
+        let parse_args = || -> Option<(ChannelId, i64, i64)> {
+            let mut args = args.split(':');
+            let source = args.next()?.parse::<u64>().ok()?;
+            let ours = args.next()?.parse::<i64>().ok()?;
+            let theirs = args.next()?.parse::<i64>().ok()?;
+            Some((ChannelId(source), ours, theirs))
+        };
+        let Some((source, our_revision, their_revision)) = parse_args() else {
+            return INVALID_REQUEST_MSG.to_owned().into();
+        };
+        let (Ok(ours), Ok(theirs)) = (
+            self.get_session(thread_id).await,
+            self.get_session(source).await,
+        ) else {
+            return "One of the sessions doesn't exist anymore."
+                .to_owned()
+                .into();
+        };
+
+        if ours.closed {
+            return Reply::from(CLOSED_SESSION_MSG.to_owned()).ephemeral();
+        }
+        if !ours.has_role(user_id, Role::Owner) {
+            return Reply::from(not_allowed(
+                user_id,
+                Role::Owner,
+                "confirm this merge",
             ))
-        });
-        if let Err(err) = send_message.await {
-            error!("Failed to send initial message to thread: {}", err);
-            return rollback_thread_creation().await;
+            .ephemeral();
+        }
+        if ours.revision_id.unwrap_or_default() != our_revision
+            || theirs.revision_id.unwrap_or_default() != their_revision
+        {
+            return Reply::from(
+                "The code changed since this merge was prepared. Use \
+                 `/merge` again."
+                    .to_owned(),
+            )
+            .ephemeral();
         }
 
-        let store = self.create_session(thread.id, user_id);
-        match store.await {
+        let merge = match self
+            .merge_sessions(ctx, (thread_id, &ours), (source, &theirs))
+            .await
+        {
             Err(err) => {
-                error!("Failed to store session: {}", err);
-                rollback_thread_creation().await
+                error!("Failed to merge sessions: {}", err);
+                return "Failed to merge the code".to_owned().into();
             },
-            Ok(()) => "Started a new session for you : D".to_owned(),
+            Ok(None) => return unrelated_sessions(source).into(),
+            Ok(Some(merge)) => merge,
+        };
+        let run_op =
+            self.update_source_code(thread_id, user_id, |source_code| {
+                *source_code = UserCode::new(merge.text);
+                Ok(source_code.as_ref().to_owned())
+            });
+        match run_op.await {
+            Ok((code, truncation)) => {
+                // The merge can't be confirmed twice.
+                let mut message = component.message.clone();
+                let remove_buttons = message
+                    .edit(&ctx.http, |message| message.components(|c| c));
+                if let Err(err) = remove_buttons.await {
+                    error!("Failed to remove merge buttons: {}", err);
+                }
+                self.respond_in_session(&UserCode::new(code), truncation)
+                    .await
+            },
+            Err(_) => "Failed to update the code".to_owned().into(),
         }
     }
 
-    /// Execute the `/del` command.
-    async fn cmd_del_from_session(
+    /// Cancel a merge after the owner clicked its button. The
+    /// buttons are removed, so it can't be confirmed anymore.
+    async fn cancel_merge(
         &self,
-        thread_id: ChannelId,
-        user_id: UserId,
-        idx: i64,
-    ) -> String {
-        let run_op =
-            self.update_source_code(thread_id, user_id, |source_code| {
-                match source_code.del(idx) {
-                    Some(deleted) => Ok(format!("Deleted `{deleted}`")),
-                    None => Ok("Nothing to delete".to_owned()),
-                }
-            });
+        component: &MessageComponentInteraction,
+    ) -> Reply {
+        let user_id = component.user.id;
+        let run_op = self.run_session_update(
+            component.channel_id,
+            user_id,
+            Role::Owner,
+            |_| {
+                Ok(format!(
+                    "{}\n\n{} cancelled this merge.",
+                    component.message.content,
+                    user_id.mention()
+                ))
+            },
+            |_, _| async { Ok(()) },
+        );
 
         match run_op.await {
-            Ok(msg) => msg,
-            Err(op_err) => match op_err {
-                OpError::Callback(_) => INVALID_REQUEST_MSG.to_owned(),
-                OpError::NotFound(_) => "You can't deleting things outside a \
-                                         session thread."
-                    .to_owned(),
-                OpError::Update(_) => "Failed to execute deletion".to_owned(),
-                OpError::NotAllowed => format!(
-                    "Hey {}! You are not allowed to delete stuff here.",
-                    user_id.mention()
-                ),
+            Ok(msg) => Reply::from(msg).replacing(),
+            Err(op_err) => Reply::from(match op_err {
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "cancel this merge")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+                _ => "This session doesn't exist anymore.".to_owned(),
+            })
+            .ephemeral(),
+        }
+    }
+
+    /// Handle the buttons under a session's responses. They
+    /// need the same roles as the commands that do the same.
+    async fn press_session_button(
+        &self,
+        ctx: &Context,
+        component: &MessageComponentInteraction,
+    ) -> Reply {
+        let thread_id = component.channel_id;
+        let user_id = component.user.id;
+        let id = component.data.custom_id.as_str();
+        match id {
+            BUTTON_UNDO => {
+                return self
+                    .cmd_step_history(thread_id, user_id, HistoryStep::Undo)
+                    .await;
+            },
+            BUTTON_DEL => {
+                return self.cmd_del_from_session(thread_id, user_id, 0).await;
+            },
+            _ => {},
+        }
+
+        let Ok(session) = self.get_session(thread_id).await else {
+            return Reply::from("This session doesn't exist anymore.".to_owned())
+                .ephemeral();
+        };
+        if !session.has_role(user_id, Role::Viewer) {
+            return Reply::from(not_allowed(
+                user_id,
+                Role::Viewer,
+                "use this button",
+            ))
+            .ephemeral();
+        }
+
+        match id {
+            BUTTON_RERUN => {
+                let code = &session.source_code;
+                self.respond_in_session(code, session.truncation).await
+            },
+            BUTTON_FULL_OUTPUT => {
+                let truncation = Truncation {
+                    sexpr: usize::MAX,
+                    value: usize::MAX,
+                };
+                let response =
+                    self.respond(&session.source_code, truncation).await;
+                Reply::from(response).ephemeral()
             },
+            _ => self.share_session(ctx, thread_id, user_id, &session).await,
         }
     }
 
-    /// Execute the `/collab` command.
-    async fn cmd_invite_collaborator(
+    /// Post a session's code and its results in the channel the
+    /// session's thread is in, for everyone to see. Viewers
+    /// can't make a session's code public.
+    async fn share_session(
         &self,
+        ctx: &Context,
         thread_id: ChannelId,
         user_id: UserId,
-        invited_id: UserId,
-    ) -> String {
-        let run_op = self.update_user_ids(thread_id, user_id, |user_ids| {
-            user_ids.push(invited_id);
-            Ok(format!(
-                "{} is now part of this session",
-                invited_id.mention()
+        session: &UserSession,
+    ) -> Reply {
+        if !session.has_role(user_id, Role::Editor) {
+            return Reply::from(not_allowed(
+                user_id,
+                Role::Editor,
+                "share this session",
             ))
-        });
+            .ephemeral();
+        }
+        let Some(channel_id) = parent_channel(ctx, thread_id).await else {
+            return Reply::from("Can't share this session :(".to_owned())
+                .ephemeral();
+        };
+
+        let response = self
+            .respond(&session.source_code, session.truncation)
+            .await;
+        let header = format!("{} shared this code:", user_id.mention());
+        let share = async {
+            channel_id.say(&ctx.http, header).await?;
+            Reply::from(response).send(&ctx.http, channel_id).await
+        };
+        if let Err(err) = share.await {
+            error!("Failed to share session: {}", err);
+            return Reply::from("Failed to share the code :(".to_owned())
+                .ephemeral();
+        }
+        Reply::from(format!("Shared the code in {}", channel_id.mention()))
+            .ephemeral()
+    }
+
+    /// Execute the "Run as Lisp" message command. The
+    /// message's code is evaluated in a fresh environment,
+    /// like `/eval` does outside of sessions.
+    async fn cmd_run_message(
+        &self,
+        command: &ApplicationCommandInteraction,
+    ) -> Reply {
+        let Some(ResolvedTarget::Message(message)) = command.data.target()
+        else {
+            return Reply::from(INVALID_REQUEST_MSG.to_owned()).ephemeral();
+        };
+        let code = message.content.strip_discord_code();
+        if code.is_empty() {
+            return Reply::from("There's no code in that message.".to_owned())
+                .ephemeral();
+        }
+
+        let response =
+            self.respond(&UserCode::new(code), Truncation::default()).await;
+        Reply::from(response).ephemeral()
+    }
+
+    /// Execute the `/truncate` command. Limits that aren't
+    /// given stay the same.
+    async fn cmd_set_truncation(
+        &self,
+        thread_id: ChannelId,
+        user_id: UserId,
+        sexpr: Option<usize>,
+        value: Option<usize>,
+    ) -> String {
+        let run_op = self.run_session_update(
+            thread_id,
+            user_id,
+            Role::Editor,
+            |session| {
+                let truncation = &mut session.truncation;
+                truncation.sexpr = sexpr.unwrap_or(truncation.sexpr);
+                truncation.value = value.unwrap_or(truncation.value);
+                Ok(format!(
+                    "Showing up to {} characters of each expression and {} \
+                     characters of each value",
+                    truncation.sexpr, truncation.value
+                ))
+            },
+            |thread_id, updated_session| {
+                self.update_session_truncation(
+                    thread_id,
+                    updated_session.truncation,
+                )
+            },
+        );
 
         match run_op.await {
             Ok(msg) => msg,
             Err(op_err) => match op_err {
                 OpError::Callback(_) => INVALID_REQUEST_MSG.to_owned(),
                 OpError::NotFound(_) => {
-                    "You can't collaborate outside of a session.".to_owned()
+                    "You can only change this inside a session.".to_owned()
                 },
-                OpError::Update(_) => "Failed to create invite".to_owned(),
-                OpError::NotAllowed => format!(
-                    "Hey {}! This is not your own session, you can't invite \
-                     people.",
-                    user_id.mention()
-                ),
+                OpError::Update(_) => "Failed to change the limits".to_owned(),
+                OpError::NotAllowed(needed) => {
+                    not_allowed(user_id, needed, "change this session")
+                },
+                OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
             },
         }
     }
@@ -330,7 +2604,7 @@ impl Bot {
         &self,
         orig_channel: ChannelId,
         sexpr: Option<&str>,
-    ) -> anyhow::Result<String> {
+    ) -> anyhow::Result<Reply> {
         match self.get_session(orig_channel).await {
             Err(_) => {
                 let Some(sexpr) = sexpr else {
@@ -339,13 +2613,43 @@ impl Bot {
                     ));
                 };
                 let code = UserCode::new(sexpr);
-                Ok(code.respond())
+                Ok(self.respond(&code, Truncation::default()).await.into())
             },
-            Ok(session) => Ok(session.source_code.respond()),
+            Ok(session) => Ok(self
+                .respond_in_session(&session.source_code, session.truncation)
+                .await),
+        }
+    }
+}
+
+/// Where code is evaluated.
+pub enum Evaluator {
+    /// On a pool of threads in the bot's own process.
+    Threads(EvalPool),
+    /// In separate worker processes.
+    Process(Sandbox),
+}
+
+impl Evaluator {
+    async fn eval(
+        &self,
+        code: &UserCode,
+    ) -> Result<Option<Evaluation>, EvaluatorError> {
+        match self {
+            Self::Threads(pool) => Ok(pool.eval(code).await?),
+            Self::Process(sandbox) => Ok(sandbox.eval(code).await?),
         }
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+enum EvaluatorError {
+    #[error(transparent)]
+    Pool(#[from] PoolError),
+    #[error(transparent)]
+    Sandbox(#[from] SandboxError),
+}
+
 #[derive(Debug, thiserror::Error)]
 enum OpError {
     #[error("Operaton failed, '{0}'")]
@@ -354,70 +2658,27 @@ enum OpError {
     Update(#[source] anyhow::Error),
     #[error("No session with thread ID {0}")]
     NotFound(ChannelId),
-    #[error("Caller was not allowed to work on the session")]
-    NotAllowed,
+    #[error("Caller needs to be {} to work on the session", .0.with_article())]
+    NotAllowed(Role),
+    #[error("The session is closed")]
+    Closed,
 }
 
 #[async_trait]
 impl EventHandler for Bot {
     async fn ready(&self, ctx: Context, ready: Ready) {
-        fn create_app_commands(
-            commands: &mut CreateApplicationCommands,
-        ) -> &mut CreateApplicationCommands {
-            commands
-                .create_application_command(|command| {
-                    command
-                        .name(CMD_EVAL)
-                        .description("Evaluate Lisp code")
-                        .create_option(|option| {
-                            option
-                                .name(CMD_EVAL_SEXPR)
-                                .description("S-expression to evaluate")
-                                .kind(CommandOptionType::String)
-                                .required(false)
-                        })
-                })
-                .create_application_command(|command| {
-                    command
-                        .name(CMD_SESSION)
-                        .description("Start a Lisp coding session")
-                })
-                .create_application_command(|command| {
-                    command
-                        .name(CMD_DEL)
-                        .description("Delete an S-expression in this session")
-                        .create_option(|option| {
-                            option
-                                .name(CMD_DEL_IDX)
-                                .description(
-                                    "Index of line to delete (last one starts \
-                                     at index 0)",
-                                )
-                                .kind(CommandOptionType::Integer)
-                                .required(false)
-                        })
-                })
-                .create_application_command(|command| {
-                    command
-                        .name(CMD_COLLAB)
-                        .description(
-                            "Invite someone to collaborate on a session with \
-                             you",
-                        )
-                        .create_option(|option| {
-                            option
-                                .name(CMD_COLLAB_WHO)
-                                .description(
-                                    "The person or role you want to invite",
-                                )
-                                .kind(CommandOptionType::Mentionable)
-                                .required(true)
-                        })
-                })
-        }
-
         info!("{} is connected!", ready.user.name);
 
+        // `ready` is called again after reconnecting, but idle
+        // sessions only need to be closed by one task.
+        if !self.expiring.swap(true, Ordering::Relaxed) {
+            tokio::spawn(expire_idle_sessions(
+                self.db.clone(),
+                ctx.http.clone(),
+                self.max_idle,
+            ));
+        }
+
         // Create global commands when in release build.
         #[cfg(not(debug_assertions))]
         Command::set_global_application_commands(
@@ -441,27 +2702,29 @@ impl EventHandler for Bot {
     async fn message(&self, ctx: Context, msg: Message) {
         if msg.kind == MessageType::Regular && !msg.author.bot {
             let thread_id = msg.channel_id;
-            let run_op = self.update_source_code(
+            let run_op = self.update_source_code_from(
                 thread_id,
                 msg.author.id,
+                Some(SourceMessage::sent(msg.id)),
                 |source_code| {
                     source_code.append(&msg.content);
-                    Ok(source_code.respond())
+                    Ok(source_code.as_ref().to_owned())
                 },
             );
 
-            let response = match run_op.await {
-                Ok(msg) => msg,
+            let reply: Reply = match run_op.await {
+                Ok((code, truncation)) => self
+                    .respond_in_session(&UserCode::new(code), truncation)
+                    .await,
                 Err(op_err) => match op_err {
                     OpError::Update(_) => "Sorry, I failed to update your \
                                            code. Maybe try again."
-                        .to_owned(),
-                    OpError::NotAllowed => {
-                        format!(
-                            "Hey {}! You are not allowed edit here.",
-                            msg.author.id.mention()
-                        )
+                        .to_owned()
+                        .into(),
+                    OpError::NotAllowed(needed) => {
+                        not_allowed(msg.author.id, needed, "edit here").into()
                     },
+                    OpError::Closed => CLOSED_SESSION_MSG.to_owned().into(),
                     // Don't react to messages in non-session channels.
                     OpError::NotFound(_) => return,
                     OpError::Callback(_) => {
@@ -470,16 +2733,94 @@ impl EventHandler for Bot {
                 },
             };
 
-            if let Err(e) = thread_id.say(&ctx.http, response).await {
-                error!("Failed to respond with new code, {}", e);
-            }
+            if let Err(e) = reply.send(&ctx.http, thread_id).await {
+                error!("Failed to respond with new code, {}", e);
+            }
+        }
+    }
+
+    async fn message_update(&self, ctx: Context, event: MessageUpdateEvent) {
+        // Only edits of the content change the code. Other
+        // updates, like embeds being added, don't come with a
+        // newer edit time.
+        let (Some(content), Some(edited_at)) =
+            (&event.content, event.edited_timestamp)
+        else {
+            return;
+        };
+        let message = SourceMessage {
+            id: event.id,
+            edited_at: Some(edited_at),
+            deleted: false,
+        };
+        self.sync_message(&ctx, event.channel_id, message, content)
+            .await;
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        _guild_id: Option<GuildId>,
+    ) {
+        let message = SourceMessage {
+            deleted: true,
+            ..SourceMessage::sent(message_id)
+        };
+        self.sync_message(&ctx, channel_id, message, "").await;
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        message_ids: Vec<MessageId>,
+        _guild_id: Option<GuildId>,
+    ) {
+        for message_id in message_ids {
+            let message = SourceMessage {
+                deleted: true,
+                ..SourceMessage::sent(message_id)
+            };
+            self.sync_message(&ctx, channel_id, message, "").await;
+        }
+    }
+
+    async fn channel_delete(&self, _ctx: Context, channel: &GuildChannel) {
+        if let Err(err) = self.delete_sessions(channel.id).await {
+            error!("Failed to delete sessions in {}: {}", channel.id, err);
+        }
+    }
+
+    async fn thread_delete(&self, _ctx: Context, thread: PartialGuildChannel) {
+        if let Err(err) = self.delete_sessions(thread.id).await {
+            error!("Failed to delete session in {}: {}", thread.id, err);
         }
     }
 
     #[allow(clippy::too_many_lines)]
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         if let Interaction::ApplicationCommand(command) = interaction {
-            let response_content = match command.data.name.as_str() {
+            // Evaluating code takes longer than Discord waits for
+            // responses, so commands are acknowledged right away.
+            // The editor can't wait, since it's the response.
+            let deferral = (command.data.name != CMD_EDIT).then(|| {
+                let ephemeral = matches!(
+                    command.data.name.as_str(),
+                    CMD_SESSIONS | CMD_RUN_MESSAGE | CMD_INVITE_USER
+                );
+                Deferral::Message { ephemeral }
+            });
+            if let Some(deferral) = deferral {
+                let defer = command.defer_response(&ctx.http, deferral);
+                if let Err(why) = defer.await {
+                    error!("Cannot defer slash command: {}", why);
+                    return;
+                }
+            }
+
+            let reply: Reply = match command.data.name.as_str() {
                 CMD_EVAL => {
                     let get_optional_sexpr = || -> anyhow::Result<&str> {
                         let option = command
@@ -513,8 +2854,9 @@ impl EventHandler for Bot {
                                  at you {})",
                                 command.user.id.mention()
                             )
+                            .into()
                         },
-                        Ok(response) => response,
+                        Ok(reply) => reply,
                     }
                 },
                 CMD_SESSION => {
@@ -522,9 +2864,15 @@ impl EventHandler for Bot {
                         &ctx,
                         command.channel_id,
                         command.user.id,
+                        None,
                     )
                     .await
+                    .into()
                 },
+                CMD_FORK => self
+                    .cmd_fork(&ctx, command.channel_id, command.user.id)
+                    .await
+                    .into(),
                 CMD_DEL => {
                     let thread_id = command.channel_id;
                     let user_id = command.user.id;
@@ -552,7 +2900,9 @@ impl EventHandler for Bot {
                             error!(
                                 "Failed to get `/del` command argument: {err}"
                             );
-                            "You must specify which line to delete".to_owned()
+                            "You must specify which line to delete"
+                                .to_owned()
+                                .into()
                         },
                         Ok(idx) => {
                             self.cmd_del_from_session(thread_id, user_id, idx)
@@ -563,113 +2913,1252 @@ impl EventHandler for Bot {
                 CMD_COLLAB => {
                     let thread_id = command.channel_id;
                     let user_id = command.user.id;
-                    let get_invited_id = || -> anyhow::Result<UserId> {
-                        let other = command
-                            .data
-                            .options
-                            .iter()
-                            .find(|opt| opt.name == CMD_COLLAB_WHO);
-                        let other = other
-                            .ok_or_else(|| {
-                                anyhow!("Missing command data option")
-                            })?
-                            .resolved
-                            .as_ref()
-                            .ok_or_else(|| anyhow!("Missing resolved value"))?;
-                        let CommandDataOptionValue::User(user, _) = other
-                        else {
-                            return Err(anyhow!(
-                                "Wrong command data option value type"
-                            ));
-                        };
-                        Ok(user.id)
+                    // Invited people can edit by default.
+                    let role = match option_value(&command, CMD_COLLAB_ROLE) {
+                        Some(CommandDataOptionValue::String(role)) => {
+                            role.parse().unwrap_or(Role::Editor)
+                        },
+                        _ => Role::Editor,
+                    };
+                    let invitees = match option_value(&command, CMD_COLLAB_WHO)
+                    {
+                        Some(CommandDataOptionValue::User(user, _)) => {
+                            Some(Invitees::User(user.id))
+                        },
+                        Some(CommandDataOptionValue::Role(role)) => {
+                            Some(Invitees::Role(role.guild_id, role.id))
+                        },
+                        _ => None,
                     };
 
-                    match get_invited_id() {
-                        Err(err) => {
+                    match invitees {
+                        None => {
                             error!(
                                 "Failed to get `/collab` command argument: \
-                                 {err}"
+                                 Missing or wrong option value"
                             );
                             "You must specify who to add to this session"
                                 .to_owned()
+                                .into()
+                        },
+                        Some(invitees) => self
+                            .cmd_invite_collaborator(
+                                &ctx, thread_id, user_id, invitees, role,
+                            )
+                            .await
+                            .into(),
+                    }
+                },
+                CMD_KICK | CMD_TRANSFER => {
+                    let thread_id = command.channel_id;
+                    let user_id = command.user.id;
+                    match option_value(&command, CMD_MEMBER) {
+                        Some(CommandDataOptionValue::User(member, _))
+                            if command.data.name == CMD_KICK =>
+                        {
+                            self.cmd_kick(&ctx, thread_id, user_id, member.id)
+                                .await
+                        },
+                        Some(CommandDataOptionValue::User(member, _)) => {
+                            self.cmd_transfer(
+                                &ctx,
+                                command.guild_id,
+                                thread_id,
+                                user_id,
+                                member.id,
+                            )
+                            .await
+                        },
+                        _ => "You must specify a member".to_owned(),
+                    }
+                    .into()
+                },
+                CMD_LEAVE => self
+                    .cmd_leave(&ctx, command.channel_id, command.user.id)
+                    .await
+                    .into(),
+                CMD_SESSIONS => self.cmd_sessions(command.user.id, 0).await,
+                CMD_RUN_MESSAGE => self.cmd_run_message(&command).await,
+                CMD_INVITE_USER => {
+                    match self.cmd_open_invite_menu(&ctx, &command).await {
+                        Some(reply) => reply,
+                        // The menu is the response.
+                        None => return,
+                    }
+                },
+                CMD_CLOSE => self
+                    .cmd_close(command.channel_id, command.user.id)
+                    .await
+                    .into(),
+                CMD_SHOW => self.cmd_show(command.channel_id).await.into(),
+                CMD_INSERT | CMD_REPLACE => {
+                    let line = match option_value(&command, CMD_EDIT_LINE) {
+                        Some(CommandDataOptionValue::Integer(line)) => {
+                            usize::try_from(*line).ok()
                         },
-                        Ok(invited_id) => {
-                            self.cmd_invite_collaborator(
-                                thread_id, user_id, invited_id,
+                        _ => None,
+                    };
+                    let code = match option_value(&command, CMD_EDIT_CODE) {
+                        Some(CommandDataOptionValue::String(code)) => {
+                            Some(code.as_str())
+                        },
+                        _ => None,
+                    };
+
+                    match (line, code) {
+                        (Some(line), Some(code)) => {
+                            let edit = if command.data.name == CMD_INSERT {
+                                LineEdit::Insert(line, code)
+                            } else {
+                                LineEdit::Replace(line, code)
+                            };
+                            self.cmd_edit_line(
+                                command.channel_id,
+                                command.user.id,
+                                edit,
                             )
                             .await
                         },
+                        _ => {
+                            error!(
+                                "Failed to get `/{}` command arguments",
+                                command.data.name
+                            );
+                            "You must specify a line and some code"
+                                .to_owned()
+                                .into()
+                        },
+                    }
+                },
+                CMD_UNDO | CMD_REDO => {
+                    let step = if command.data.name == CMD_UNDO {
+                        HistoryStep::Undo
+                    } else {
+                        HistoryStep::Redo
+                    };
+                    self.cmd_step_history(
+                        command.channel_id,
+                        command.user.id,
+                        step,
+                    )
+                    .await
+                },
+                CMD_EDIT => match self.cmd_open_editor(&ctx, &command).await {
+                    Some(reply) => reply,
+                    // The editor is the response.
+                    None => return,
+                },
+                CMD_HISTORY => {
+                    self.cmd_history(command.channel_id).await.into()
+                },
+                CMD_BLAME => {
+                    self.cmd_blame(&ctx, command.channel_id).await.into()
+                },
+                CMD_CHECKOUT => {
+                    match option_value(&command, CMD_REVISION) {
+                        Some(CommandDataOptionValue::Integer(number)) => self
+                            .cmd_checkout(
+                                command.channel_id,
+                                command.user.id,
+                                usize::try_from(*number).unwrap_or_default(),
+                            )
+                            .await,
+                        _ => "You must specify a revision".to_owned().into(),
+                    }
+                },
+                CMD_DIFF => {
+                    let get_number = |name: &str| -> Option<usize> {
+                        match option_value(&command, name)? {
+                            CommandDataOptionValue::Integer(number) => {
+                                usize::try_from(*number).ok()
+                            },
+                            _ => None,
+                        }
+                    };
+
+                    match (get_number(CMD_DIFF_OLD), get_number(CMD_DIFF_NEW))
+                    {
+                        (Some(old), Some(new)) => {
+                            self.cmd_diff(command.channel_id, old, new).await
+                        },
+                        _ => "You must specify two revisions".to_owned().into(),
                     }
                 },
+                CMD_MERGE => match option_value(&command, CMD_MERGE_FROM) {
+                    Some(CommandDataOptionValue::Channel(source)) => {
+                        self.cmd_merge(
+                            &ctx,
+                            command.channel_id,
+                            command.user.id,
+                            source.id,
+                        )
+                        .await
+                    },
+                    _ => "You must specify a session to merge"
+                        .to_owned()
+                        .into(),
+                },
+                CMD_TRUNCATE => {
+                    let get_limit = |name: &str| -> Option<usize> {
+                        match option_value(&command, name)? {
+                            CommandDataOptionValue::Integer(limit) => {
+                                usize::try_from(*limit).ok()
+                            },
+                            _ => None,
+                        }
+                    };
+
+                    self.cmd_set_truncation(
+                        command.channel_id,
+                        command.user.id,
+                        get_limit(CMD_TRUNCATE_SEXPR),
+                        get_limit(CMD_TRUNCATE_VALUE),
+                    )
+                    .await
+                    .into()
+                },
                 command => unreachable!("Unknown command: {}", command),
             };
 
-            let create_response =
-                command.create_interaction_response(&ctx.http, |response| {
-                    response
-                        .kind(InteractionResponseType::ChannelMessageWithSource)
-                        .interaction_response_data(|message| {
-                            message.content(response_content)
-                        })
-                });
-
-            if let Err(why) = create_response.await {
+            let respond = match deferral {
+                Some(deferral) => {
+                    reply
+                        .respond_deferred(&ctx.http, &command, deferral)
+                        .await
+                },
+                None => reply.respond_to(&ctx.http, &command).await,
+            };
+            if let Err(why) = respond {
                 error!("Cannot respond to slash command: {}", why);
             }
+
+            // Nobody can respond in the thread once it's locked,
+            // so it's only archived after the response.
+            if command.data.name == CMD_CLOSE {
+                let session = self.get_session(command.channel_id).await;
+                if session.is_ok_and(|session| session.closed) {
+                    archive_thread(&ctx.http, command.channel_id).await;
+                }
+            }
+        } else if let Interaction::MessageComponent(component) = interaction {
+            let defer = component.defer_response(&ctx.http, Deferral::Update);
+            if let Err(why) = defer.await {
+                error!("Cannot defer component: {}", why);
+                return;
+            }
+
+            let id = component.data.custom_id.as_str();
+            let reply: Reply = if id == BUTTON_MERGE_CANCEL {
+                self.cancel_merge(&component).await
+            } else if let Some(args) = id
+                .strip_prefix(BUTTON_MERGE)
+                .and_then(|args| args.strip_prefix(':'))
+            {
+                self.confirm_merge(&ctx, &component, args).await
+            } else if let Some(invited) = id
+                .strip_prefix(SELECT_INVITE)
+                .and_then(|invited| invited.strip_prefix(':'))
+            {
+                self.invite_from_menu(&ctx, &component, invited)
+                    .await
+                    .replacing()
+            } else if SESSION_BUTTONS.contains(&id) {
+                self.press_session_button(&ctx, &component).await
+            } else if let Some(page) = id
+                .strip_prefix(BUTTON_SESSIONS)
+                .and_then(|page| page.strip_prefix(':'))
+            {
+                match page.parse::<usize>() {
+                    Ok(page) => self
+                        .cmd_sessions(component.user.id, page)
+                        .await
+                        .replacing(),
+                    Err(_) => INVALID_REQUEST_MSG.to_owned().into(),
+                }
+            } else {
+                error!("Unknown component: {}", id);
+                return;
+            };
+
+            let respond =
+                reply.respond_deferred(&ctx.http, &component, Deferral::Update);
+            if let Err(why) = respond.await {
+                error!("Cannot respond to component: {}", why);
+            }
+        } else if let Interaction::ModalSubmit(modal) = interaction {
+            let deferral = Deferral::Message { ephemeral: false };
+            if let Err(why) = modal.defer_response(&ctx.http, deferral).await {
+                error!("Cannot defer modal: {}", why);
+                return;
+            }
+
+            let id = modal.data.custom_id.as_str();
+            let reply = if let Some(args) = id
+                .strip_prefix(MODAL_EDIT)
+                .and_then(|args| args.strip_prefix(':'))
+            {
+                self.submit_editor(&modal, args).await
+            } else {
+                error!("Unknown modal: {}", id);
+                return;
+            };
+
+            let respond = reply.respond_deferred(&ctx.http, &modal, deferral);
+            if let Err(why) = respond.await {
+                error!("Cannot respond to modal: {}", why);
+            }
+        }
+    }
+}
+
+/// Register all of the bot's commands.
+fn create_app_commands(
+    commands: &mut CreateApplicationCommands,
+) -> &mut CreateApplicationCommands {
+    create_eval_commands(commands);
+    create_session_commands(commands);
+    create_member_commands(commands);
+    create_edit_commands(commands);
+    create_history_commands(commands);
+    commands
+}
+
+/// Commands that evaluate code.
+fn create_eval_commands(
+    commands: &mut CreateApplicationCommands,
+) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command
+                .name(CMD_EVAL)
+                .description("Evaluate Lisp code")
+                .create_option(|option| {
+                    option
+                        .name(CMD_EVAL_SEXPR)
+                        .description("S-expression to evaluate")
+                        .kind(CommandOptionType::String)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command.name(CMD_RUN_MESSAGE).kind(CommandType::Message)
+        })
+}
+
+/// Commands that start, list and manage sessions.
+fn create_session_commands(
+    commands: &mut CreateApplicationCommands,
+) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command
+                .name(CMD_SESSION)
+                .description("Start a Lisp coding session")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_SESSIONS)
+                .description("List the sessions you're part of")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_FORK)
+                .description("Copy this session into a new one")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_MERGE)
+                .description("Merge a fork or its parent session into this one")
+                .create_option(|option| {
+                    option
+                        .name(CMD_MERGE_FROM)
+                        .description("Thread of the other session")
+                        .kind(CommandOptionType::Channel)
+                        .channel_types(&[ChannelType::PrivateThread])
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_CLOSE)
+                .description("Make this session read-only")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_TRUNCATE)
+                .description(
+                    "Change how much of long expressions and values \
+                     is shown",
+                )
+                .create_option(|option| {
+                    option
+                        .name(CMD_TRUNCATE_SEXPR)
+                        .description("Characters shown of each expression")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(TRUNCATION_MIN)
+                        .max_int_value(TRUNCATION_MAX)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name(CMD_TRUNCATE_VALUE)
+                        .description("Characters shown of each value")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(TRUNCATION_MIN)
+                        .max_int_value(TRUNCATION_MAX)
+                        .required(false)
+                })
+        })
+}
+
+/// Commands that change who is part of a session.
+fn create_member_commands(
+    commands: &mut CreateApplicationCommands,
+) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command.name(CMD_INVITE_USER).kind(CommandType::User)
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_COLLAB)
+                .description(
+                    "Invite someone to collaborate on a session with \
+                     you",
+                )
+                .create_option(|option| {
+                    option
+                        .name(CMD_COLLAB_WHO)
+                        .description("The person or role you want to invite")
+                        .kind(CommandOptionType::Mentionable)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name(CMD_COLLAB_ROLE)
+                        .description(
+                            "What they may do, editors can change \
+                             the code",
+                        )
+                        .kind(CommandOptionType::String)
+                        .add_string_choice("editor", Role::Editor.as_str())
+                        .add_string_choice("viewer", Role::Viewer.as_str())
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_KICK)
+                .description("Remove someone from this session")
+                .create_option(|option| {
+                    option
+                        .name(CMD_MEMBER)
+                        .description("The person to remove")
+                        .kind(CommandOptionType::User)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command.name(CMD_LEAVE).description("Leave this session")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_TRANSFER)
+                .description("Hand this session over to someone else")
+                .create_option(|option| {
+                    option
+                        .name(CMD_MEMBER)
+                        .description("The member who becomes the owner")
+                        .kind(CommandOptionType::User)
+                        .required(true)
+                })
+        })
+}
+
+/// Commands that show and edit a session's code.
+fn create_edit_commands(
+    commands: &mut CreateApplicationCommands,
+) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command
+                .name(CMD_SHOW)
+                .description("Show this session's code with line numbers")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_DEL)
+                .description("Delete an S-expression in this session")
+                .create_option(|option| {
+                    option
+                        .name(CMD_DEL_IDX)
+                        .description(
+                            "Index of line to delete (last one starts \
+                             at index 0)",
+                        )
+                        .kind(CommandOptionType::Integer)
+                        .required(false)
+                })
+        })
+        .create_application_command(|command| {
+            create_line_edit_command(
+                command,
+                CMD_INSERT,
+                "Insert code in front of a line in this session",
+            )
+        })
+        .create_application_command(|command| {
+            create_line_edit_command(
+                command,
+                CMD_REPLACE,
+                "Replace a line in this session",
+            )
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_EDIT)
+                .description("Edit this session's code in an editor")
+                .create_option(|option| {
+                    option
+                        .name(CMD_EDIT_FROM)
+                        .description("First line to edit, the first is 1")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(false)
+                })
+                .create_option(|option| {
+                    option
+                        .name(CMD_EDIT_TO)
+                        .description("Last line to edit")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(false)
+                })
+        })
+}
+
+/// Commands that go through a session's revisions.
+fn create_history_commands(
+    commands: &mut CreateApplicationCommands,
+) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| {
+            command
+                .name(CMD_UNDO)
+                .description("Undo the last change to this session")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_REDO)
+                .description("Redo the last change that was undone")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_HISTORY)
+                .description("List the revisions of this session")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_BLAME)
+                .description("Show who wrote each line of this session")
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_CHECKOUT)
+                .description("Restore a revision of this session")
+                .create_option(|option| {
+                    option
+                        .name(CMD_REVISION)
+                        .description("Number of the revision")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+        })
+        .create_application_command(|command| {
+            command
+                .name(CMD_DIFF)
+                .description("Compare two revisions of this session")
+                .create_option(|option| {
+                    option
+                        .name(CMD_DIFF_OLD)
+                        .description("Number of the older revision")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+                .create_option(|option| {
+                    option
+                        .name(CMD_DIFF_NEW)
+                        .description("Number of the newer revision")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+        })
+}
+
+fn create_line_edit_command<'a>(
+    command: &'a mut CreateApplicationCommand,
+    name: &str,
+    description: &str,
+) -> &'a mut CreateApplicationCommand {
+    command
+        .name(name)
+        .description(description)
+        .create_option(|option| {
+            option
+                .name(CMD_EDIT_LINE)
+                .description("Number of the line, the first is 1")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .required(true)
+        })
+        .create_option(|option| {
+            option
+                .name(CMD_EDIT_CODE)
+                .description("The code to put there")
+                .kind(CommandOptionType::String)
+                .required(true)
+        })
+}
+
+/// What `/sessions` shows about a session.
+struct SessionSummary {
+    thread_id:      ChannelId,
+    /// The user's role in the session.
+    role:           Role,
+    /// Seconds since the Unix epoch.
+    last_active_at: i64,
+    lines:          usize,
+    closed:         bool,
+}
+
+/// A change to a line of a session's code.
+enum LineEdit<'a> {
+    Insert(usize, &'a str),
+    Replace(usize, &'a str),
+}
+
+/// A revision of a session's code. Revisions are numbered
+/// from 1 in the order they were made.
+struct Revision {
+    number:      usize,
+    id:          i64,
+    parent_id:   Option<i64>,
+    source_code: String,
+    /// Nobody, if a message was deleted.
+    author:      Option<UserId>,
+    /// Seconds since the Unix epoch.
+    created_at:  i64,
+    /// Is this the session's current revision?
+    current:     bool,
+}
+
+/// Where a line of a session's code came from.
+#[derive(Debug, Clone, Copy)]
+struct LineOrigin {
+    /// Who last changed the line, if it's known.
+    author:     Option<UserId>,
+    /// The message the line was last changed in, if any.
+    message_id: Option<MessageId>,
+}
+
+/// The message that a new revision's code came from.
+#[derive(Debug, Clone, Copy)]
+struct SourceMessage {
+    id:        MessageId,
+    /// When the message was edited, if the revision is an edit.
+    edited_at: Option<Timestamp>,
+    /// Whether the message was deleted, which removes its code.
+    deleted:   bool,
+}
+
+impl SourceMessage {
+    const fn sent(id: MessageId) -> Self {
+        Self {
+            id,
+            edited_at: None,
+            deleted: false,
         }
     }
 }
 
+/// Get the revision that last changed each line of a
+/// revision's code from what's stored about it. If nothing
+/// or something that doesn't fit is stored, the revision
+/// itself changed every line.
+fn line_revisions(id: i64, code: &str, stored: Option<Vec<i64>>) -> Vec<i64> {
+    let count = code.lines().count();
+    match stored {
+        Some(stored) if stored.len() == count => stored,
+        _ => vec![id; count],
+    }
+}
+
+/// Get up to two initials from a name, e.g. "AL" from
+/// "ada_lovelace".
+fn initials(name: &str) -> String {
+    let words = name
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>();
+    let initials = match words.as_slice() {
+        [] => "?".to_owned(),
+        [word] => word.chars().take(2).collect(),
+        [first, second, ..] => {
+            first.chars().take(1).chain(second.chars().take(1)).collect()
+        },
+    };
+    initials.to_uppercase()
+}
+
+/// Summarize a change to some code by counting the lines
+/// that changed and showing the first one.
+fn summarize(old: &str, new: &str) -> String {
+    let diff = diff::lines(old, new);
+    let (added, removed) = diff::stats(&diff);
+    let first = diff.iter().find_map(|line| match line {
+        diff::Line::Same(_) => None,
+        diff::Line::Removed(line) | diff::Line::Added(line) => Some(line),
+    });
+    match first {
+        None => "no changes".to_owned(),
+        Some(line) => {
+            let line = line
+                .trim()
+                .chars()
+                .filter(|c| *c != '`')
+                .take(SUMMARY_LEN)
+                .collect::<String>();
+            format!("+{added} -{removed} `{line}`")
+        },
+    }
+}
+
+/// A step through a session's revisions.
+#[derive(Debug, Clone, Copy)]
+enum HistoryStep {
+    Undo,
+    Redo,
+}
+
+/// Get the channel that a session's thread is in.
+async fn parent_channel(
+    ctx: &Context,
+    thread_id: ChannelId,
+) -> Option<ChannelId> {
+    match thread_id.to_channel(ctx).await {
+        Ok(Channel::Guild(thread)) => thread.parent_id,
+        Ok(_) => None,
+        Err(err) => {
+            error!("Failed to get session thread: {}", err);
+            None
+        },
+    }
+}
+
+/// Get the value of a command's option by its name.
+fn option_value<'a>(
+    command: &'a ApplicationCommandInteraction,
+    name: &str,
+) -> Option<&'a CommandDataOptionValue> {
+    command
+        .data
+        .options
+        .iter()
+        .find(|opt| opt.name == name)?
+        .resolved
+        .as_ref()
+}
+
 #[derive(Debug)]
 struct UserSession {
-    user_ids:    Vec<UserId>,
+    members:     Vec<(UserId, Role)>,
     source_code: UserCode,
+    truncation:  Truncation,
+    /// The current revision, if there is one.
+    revision_id: Option<i64>,
+    /// Closed sessions are read-only.
+    closed:      bool,
 }
 
 impl UserSession {
-    fn new(user_ids: Vec<UserId>, source_code: String) -> Self {
+    fn new(
+        members: Vec<(UserId, Role)>,
+        source_code: String,
+        truncation: Truncation,
+        revision_id: Option<i64>,
+        closed: bool,
+    ) -> Self {
         Self {
-            user_ids,
+            members,
             source_code: UserCode::new(source_code),
+            truncation,
+            revision_id,
+            closed,
+        }
+    }
+}
+
+impl UserSession {
+    fn role_of(&self, user_id: UserId) -> Option<Role> {
+        self.members
+            .iter()
+            .find(|(member, _)| *member == user_id)
+            .map(|(_, role)| *role)
+    }
+
+    /// Does the user have at least the `needed` role?
+    fn has_role(&self, user_id: UserId, needed: Role) -> bool {
+        self.role_of(user_id).is_some_and(|role| role >= needed)
+    }
+
+    fn owner(&self) -> Option<UserId> {
+        self.members
+            .iter()
+            .find(|(_, role)| *role == Role::Owner)
+            .map(|(member, _)| *member)
+    }
+}
+
+/// What a member of a session may do. Each role may do
+/// everything that the roles before it may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    /// Read the code and evaluate it.
+    Viewer,
+    /// Change the code.
+    Editor,
+    /// Manage who's part of the session.
+    Owner,
+}
+
+impl Role {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Editor => "editor",
+            Self::Owner => "owner",
+        }
+    }
+
+    /// The role's name with an article, e.g. "an editor".
+    const fn with_article(self) -> &'static str {
+        match self {
+            Self::Viewer => "a viewer",
+            Self::Editor => "an editor",
+            Self::Owner => "the owner",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "viewer" => Ok(Self::Viewer),
+            "editor" => Ok(Self::Editor),
+            "owner" => Ok(Self::Owner),
+            _ => Err(anyhow!("Unknown role '{role}'")),
+        }
+    }
+}
+
+/// Who `/collab` invites.
+enum Invitees {
+    User(UserId),
+    /// Everyone with a role in a guild.
+    Role(GuildId, RoleId),
+}
+
+/// Get everyone in a guild who has a role, except bots. It
+/// stops once there are more than `limit` of them. Only the
+/// first `MAX_MEMBER_PAGES` pages of members are looked
+/// through, and if the guild has more, it's `None`.
+///
+/// Listing members needs the *Server Members Intent*.
+async fn role_members(
+    ctx: &Context,
+    guild_id: GuildId,
+    role_id: RoleId,
+    limit: usize,
+) -> Result<Option<Vec<UserId>>, anyhow::Error> {
+    let mut user_ids = Vec::new();
+    let mut after = None;
+    for _ in 0..MAX_MEMBER_PAGES {
+        let page =
+            guild_id.members(&ctx.http, Some(MEMBERS_PAGE), after).await?;
+        let done = page.len() < usize::try_from(MEMBERS_PAGE)?;
+        after = page.last().map(|member| member.user.id);
+        // Everyone has the `@everyone` role, which has the
+        // guild's ID, but it's not in their list of roles.
+        user_ids.extend(
+            page.into_iter()
+                .filter(|member| {
+                    !member.user.bot
+                        && (role_id.0 == guild_id.0
+                            || member.roles.contains(&role_id))
+                })
+                .map(|member| member.user.id),
+        );
+        if done || user_ids.len() > limit {
+            return Ok(Some(user_ids));
+        }
+    }
+    Ok(None)
+}
+
+/// Did the user leave the guild? Errors other than the
+/// user not being found don't count as leaving.
+async fn has_left(ctx: &Context, guild_id: GuildId, user_id: UserId) -> bool {
+    match guild_id.member(ctx, user_id).await {
+        Err(serenity::Error::Http(err)) => {
+            err.status_code() == Some(StatusCode::NOT_FOUND)
+        },
+        _ => false,
+    }
+}
+
+/// Tell a user why they couldn't invite people.
+fn invite_failed(user_id: UserId, op_err: OpError) -> String {
+    match op_err {
+        OpError::Callback(err) => err.to_string(),
+        OpError::NotFound(_) => {
+            "You can't collaborate outside of a session.".to_owned()
+        },
+        OpError::Update(_) => "Failed to create invite".to_owned(),
+        OpError::NotAllowed(needed) => {
+            not_allowed(user_id, needed, "invite people")
+        },
+        OpError::Closed => CLOSED_SESSION_MSG.to_owned(),
+    }
+}
+
+/// Tell a user that sessions can't be merged because neither
+/// was forked from the other.
+fn unrelated_sessions(source: ChannelId) -> String {
+    format!(
+        "You can only merge a fork into the session it came from, or \
+         the other way around. Neither this session nor {} was forked \
+         from the other.",
+        source.mention()
+    )
+}
+
+/// Tell a user that they need another role to do something.
+fn not_allowed(user_id: UserId, needed: Role, action: &str) -> String {
+    format!(
+        "Hey {}! You need to be {} to {action}.",
+        user_id.mention(),
+        needed.with_article()
+    )
+}
+
+/// Close all sessions whose code didn't change for longer
+/// than `max_idle`. Return their thread IDs.
+#[tracing::instrument(name = "Close idle sessions", skip(db), err)]
+async fn close_idle_sessions(
+    db: &PgPool,
+    max_idle: Duration,
+) -> Result<Vec<ChannelId>, anyhow::Error> {
+    let closed = sqlx::query_scalar!(
+        r#"
+        UPDATE sessions
+        SET
+            closed_at = now()
+        WHERE
+            closed_at IS NULL
+            AND last_active_at < now() - make_interval(secs => $1)
+        RETURNING thread_id
+        "#,
+        max_idle.as_secs_f64(),
+    )
+    .fetch_all(db)
+    .await?;
+
+    closed
+        .into_iter()
+        .map(|thread_id| Ok(ChannelId(thread_id.parse::<u64>()?)))
+        .collect()
+}
+
+/// Store the channel of sessions from before channels were
+/// remembered, so that deleting the channel forgets them too.
+/// Sessions whose thread no longer exists are forgotten. This
+/// only runs once at startup, so sessions whose channel can't
+/// be found aren't looked up again and again.
+#[tracing::instrument(name = "Fill in session channels", skip_all, err)]
+async fn fill_channel_ids(
+    db: &PgPool,
+    http: &Http,
+) -> Result<(), anyhow::Error> {
+    let thread_ids = sqlx::query_scalar!(
+        r#"
+        SELECT thread_id
+        FROM sessions
+        WHERE
+            channel_id IS NULL
+        "#,
+    )
+    .fetch_all(db)
+    .await?;
+
+    for thread_id in thread_ids {
+        let channel_id = match http.get_channel(thread_id.parse()?).await {
+            Ok(Channel::Guild(thread)) => thread.parent_id,
+            Ok(_) => None,
+            Err(serenity::Error::Http(err))
+                if err.status_code() == Some(StatusCode::NOT_FOUND) =>
+            {
+                sqlx::query!(
+                    "DELETE FROM sessions WHERE thread_id = $1",
+                    thread_id,
+                )
+                .execute(db)
+                .await?;
+                continue;
+            },
+            Err(err) => {
+                error!("Failed to get session thread: {}", err);
+                continue;
+            },
+        };
+        let Some(channel_id) = channel_id else {
+            continue;
+        };
+        sqlx::query!(
+            "UPDATE sessions SET channel_id = $2 WHERE thread_id = $1",
+            thread_id,
+            channel_id.to_string(),
+        )
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Regularly close sessions that have been idle for longer
+/// than `max_idle` and archive their threads.
+async fn expire_idle_sessions(db: PgPool, http: Arc<Http>, max_idle: Duration) {
+    if let Err(err) = fill_channel_ids(&db, &http).await {
+        error!("Failed to fill in session channels: {}", err);
+    }
+
+    let mut interval = time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let closed = match close_idle_sessions(&db, max_idle).await {
+            Err(err) => {
+                error!("Failed to close idle sessions: {}", err);
+                continue;
+            },
+            Ok(closed) => closed,
+        };
+
+        for thread_id in closed {
+            let announce = thread_id.say(
+                &http,
+                format!(
+                    "This session was closed because its code didn't change \
+                     for {} days.",
+                    max_idle.as_secs() / SECS_PER_DAY
+                ),
+            );
+            if let Err(err) = announce.await {
+                error!("Failed to announce closing {}: {}", thread_id, err);
+            }
+            archive_thread(&http, thread_id).await;
         }
     }
 }
 
+/// Archive and lock a closed session's thread, so that only
+/// moderators can send messages there.
+async fn archive_thread(http: &Http, thread_id: ChannelId) {
+    let archive = thread_id
+        .edit_thread(http, |thread| thread.archived(true).locked(true));
+    if let Err(err) = archive.await {
+        error!("Failed to archive thread {}: {}", thread_id, err);
+    }
+}
+
+/// Make a user the owner of a new session.
+async fn add_owner(
+    tx: &mut Transaction<'_, Postgres>,
+    thread_id: ChannelId,
+    user_id: UserId,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO session_members (thread_id, user_id, role)
+        VALUES ($1, $2, 'owner')
+        "#,
+        thread_id.to_string(),
+        user_id.to_string(),
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
 const CMD_EVAL: &str = "eval";
 const CMD_EVAL_SEXPR: &str = "sexpr";
 const CMD_SESSION: &str = "lisp";
+/// Context menu commands can have spaces in their names.
+const CMD_RUN_MESSAGE: &str = "Run as Lisp";
+const CMD_INVITE_USER: &str = "Invite to my session";
+const CMD_SESSIONS: &str = "sessions";
+const CMD_FORK: &str = "fork";
 const CMD_DEL: &str = "del";
 const CMD_DEL_IDX: &str = "index";
 const CMD_COLLAB: &str = "collab";
 const CMD_COLLAB_WHO: &str = "who";
+const CMD_COLLAB_ROLE: &str = "role";
+const CMD_KICK: &str = "kick";
+const CMD_LEAVE: &str = "leave";
+const CMD_TRANSFER: &str = "transfer";
+const CMD_MEMBER: &str = "member";
+const CMD_CLOSE: &str = "close";
+const CMD_SHOW: &str = "show";
+const CMD_INSERT: &str = "insert";
+const CMD_REPLACE: &str = "replace";
+const CMD_EDIT_LINE: &str = "line";
+const CMD_EDIT_CODE: &str = "code";
+const CMD_EDIT: &str = "edit";
+const CMD_EDIT_FROM: &str = "from";
+const CMD_EDIT_TO: &str = "to";
+const CMD_UNDO: &str = "undo";
+const CMD_REDO: &str = "redo";
+const CMD_HISTORY: &str = "history";
+const CMD_BLAME: &str = "blame";
+const CMD_CHECKOUT: &str = "checkout";
+const CMD_REVISION: &str = "revision";
+const CMD_DIFF: &str = "diff";
+const CMD_DIFF_OLD: &str = "old";
+const CMD_DIFF_NEW: &str = "new";
+const CMD_MERGE: &str = "merge";
+const CMD_MERGE_FROM: &str = "from";
+const CMD_TRUNCATE: &str = "truncate";
+const CMD_TRUNCATE_SEXPR: &str = "sexpr";
+const CMD_TRUNCATE_VALUE: &str = "value";
+
+/// Bounds for the lengths `/truncate` accepts. Results are
+/// split up anyways once they don't fit into a message.
+const TRUNCATION_MIN: u16 = 8;
+const TRUNCATION_MAX: u16 = 2000;
+
+/// Custom IDs of the buttons under a merge.
+const BUTTON_MERGE: &str = "merge";
+const BUTTON_MERGE_CANCEL: &str = "merge-cancel";
+/// Custom IDs of the buttons under a session's responses.
+const BUTTON_RERUN: &str = "rerun";
+const BUTTON_UNDO: &str = "undo";
+const BUTTON_DEL: &str = "del";
+const BUTTON_FULL_OUTPUT: &str = "full-output";
+const BUTTON_SHARE: &str = "share";
+const SESSION_BUTTONS: [&str; 5] = [
+    BUTTON_RERUN,
+    BUTTON_UNDO,
+    BUTTON_DEL,
+    BUTTON_FULL_OUTPUT,
+    BUTTON_SHARE,
+];
+/// Custom IDs of the editor that `/edit` opens and its input.
+const MODAL_EDIT: &str = "edit";
+const EDITOR_INPUT: &str = "code";
+/// Discord doesn't allow longer text in inputs.
+const EDITOR_LIMIT: usize = 4000;
+/// Custom ID of the menu to choose a session to invite to.
+const SELECT_INVITE: &str = "invite";
+/// Discord doesn't allow more options in menus.
+const MENU_LIMIT: usize = 25;
+/// Custom ID of the buttons that show pages of `/sessions`.
+const BUTTON_SESSIONS: &str = "sessions";
+
+/// Number of sessions `/sessions` lists on each page.
+const SESSIONS_PAGE: usize = 10;
+/// Number of revisions `/history` lists.
+const HISTORY_LEN: usize = 20;
+/// Characters of a changed line shown in `/history`.
+const SUMMARY_LEN: usize = 32;
+/// Unchanged lines shown around changes in `/diff`.
+const DIFF_CONTEXT: usize = 3;
+/// Guild members fetched at once, the most Discord allows.
+const MEMBERS_PAGE: u64 = 1000;
+/// Pages of guild members `/collab` looks through at most to
+/// find everyone with a role.
+const MAX_MEMBER_PAGES: u64 = 10;
+/// Number of people `/collab` invites at most.
+const MAX_INVITES: usize = 50;
+
+const CLOSED_SESSION_MSG: &str =
+    "This session is closed, so it can't be changed anymore.";
+/// How often idle sessions are looked for.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
 
 const INVALID_REQUEST_MSG: &str =
     "I received an invalid request. Maybe try again.";
 
+use std::collections::HashMap;
+use std::fmt::Write;
 use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
+use liz::diff;
+use liz::eval::{DiscordCode, Evaluation, Response, Truncation, UserCode};
 use names::{Generator, Name};
 use serenity::async_trait;
 use serenity::client::{Context, EventHandler};
+use serenity::http::{Http, StatusCode};
 #[cfg(not(debug_assertions))]
 use serenity::model::application::command::Command;
-use serenity::model::application::command::CommandOptionType;
+use serenity::model::application::command::{CommandOptionType, CommandType};
+#[rustfmt::skip]
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction,
+    CommandDataOptionValue,
+    ResolvedTarget,
+};
+#[rustfmt::skip]
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
 #[rustfmt::skip]
-use serenity::model::application::interaction::application_command::CommandDataOptionValue;
-use serenity::builder::CreateApplicationCommands;
+use serenity::model::application::interaction::modal::ModalSubmitInteraction;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommands};
+#[rustfmt::skip]
+use serenity::model::application::component::{
+    ActionRowComponent,
+    ButtonStyle,
+    InputTextStyle,
+};
 use serenity::model::application::interaction::{
     Interaction,
     InteractionResponseType,
 };
-use serenity::model::channel::{Message, MessageType};
+#[rustfmt::skip]
+use serenity::model::channel::{
+    Channel,
+    ChannelType,
+    GuildChannel,
+    Message,
+    MessageType,
+    PartialGuildChannel,
+};
 use serenity::model::gateway::Ready;
-#[cfg(debug_assertions)]
-use serenity::model::id::GuildId;
-use serenity::model::id::{ChannelId, UserId};
+use serenity::model::event::MessageUpdateEvent;
+use serenity::model::id::{ChannelId, GuildId, MessageId, RoleId, UserId};
 use serenity::model::mention::Mentionable;
-use sqlx::PgPool;
+use serenity::model::Timestamp;
+use sqlx::{PgPool, Postgres, Transaction};
+use tokio::time;
 use tracing::{error, info};
 
-use crate::eval::UserCode;
+use crate::output::{Deferral, Interactive, Reply};
+use crate::pool::{EvalPool, PoolError};
+use crate::sandbox::{Sandbox, SandboxError};
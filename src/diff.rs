@@ -0,0 +1,347 @@
+/// A line in the difference between two texts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'a> {
+    /// The line is in both texts.
+    Same(&'a str),
+    /// The line is only in the old text.
+    Removed(&'a str),
+    /// The line is only in the new text.
+    Added(&'a str),
+}
+
+/// Compare two texts line by line. Lines that are in both
+/// texts are kept, and as few lines as possible are removed
+/// and added. Texts that differ in too many lines are only
+/// compared at their start and end.
+pub fn lines<'a>(old: &'a str, new: &'a str) -> Vec<Line<'a>> {
+    let old = old.lines().collect::<Vec<&str>>();
+    let new = new.lines().collect::<Vec<&str>>();
+
+    // Most changes touch only a few lines, so the lines that
+    // are the same at the start and the end are matched up
+    // without comparing everything to everything.
+    let prefix = old.iter().zip(&new).take_while(|(o, n)| o == n).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let old_end = old.len() - suffix;
+    let new_end = new.len() - suffix;
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    diff.extend(old[..prefix].iter().map(|line| Line::Same(line)));
+    diff.extend(subsequence(&old[prefix..old_end], &new[prefix..new_end]));
+    diff.extend(old[old_end..].iter().map(|line| Line::Same(line)));
+    diff
+}
+
+/// Compare lines by finding their longest common subsequence.
+/// If the table for that would be too big, all old lines are
+/// removed and all new lines added instead.
+fn subsequence<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    if old.len().saturating_mul(new.len()) > MAX_TABLE {
+        let removed = old.iter().map(|line| Line::Removed(line));
+        let added = new.iter().map(|line| Line::Added(line));
+        return removed.chain(added).collect();
+    }
+
+    // `common[i][j]` is the length of the longest common
+    // subsequence of `old[i..]` and `new[j..]`.
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            diff.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| Line::Removed(line)));
+    diff.extend(new[j..].iter().map(|line| Line::Added(line)));
+    diff
+}
+
+/// Count the lines that were added and removed.
+pub fn stats(diff: &[Line]) -> (usize, usize) {
+    diff.iter().fold((0, 0), |(added, removed), line| match line {
+        Line::Same(_) => (added, removed),
+        Line::Removed(_) => (added, removed + 1),
+        Line::Added(_) => (added + 1, removed),
+    })
+}
+
+/// Carry something attached to each line of the old text
+/// over to the lines of the new text that were kept. Lines
+/// that were added get `added` instead.
+pub fn carry<T>(old: &str, new: &str, attached: &[T], added: T) -> Vec<T>
+where
+    T: Clone,
+{
+    let mut attached = attached.iter();
+    lines(old, new)
+        .into_iter()
+        .filter_map(|line| match line {
+            Line::Same(_) => attached.next().cloned(),
+            Line::Removed(_) => {
+                attached.next();
+                None
+            },
+            Line::Added(_) => Some(added.clone()),
+        })
+        .collect()
+}
+
+/// For each line of the old text, the index of the line of
+/// the new text that it was kept as.
+fn kept(old: &str, new: &str) -> Vec<Option<usize>> {
+    let mut kept = Vec::new();
+    let mut new_idx = 0;
+    for line in lines(old, new) {
+        match line {
+            Line::Same(_) => {
+                kept.push(Some(new_idx));
+                new_idx += 1;
+            },
+            Line::Removed(_) => kept.push(None),
+            Line::Added(_) => new_idx += 1,
+        }
+    }
+    kept
+}
+
+/// Two versions of a text merged into one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Merge {
+    pub text:      String,
+    /// Number of places where both versions changed the same
+    /// lines differently.
+    pub conflicts: usize,
+}
+
+/// Merge the changes that two versions made to their common
+/// base. Conflicting changes are both kept between markers
+/// that show the versions' `labels`.
+pub fn merge(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    labels: [&str; 2],
+) -> Merge {
+    let base_lines = base.lines().collect::<Vec<&str>>();
+    let our_lines = ours.lines().collect::<Vec<&str>>();
+    let their_lines = theirs.lines().collect::<Vec<&str>>();
+    let in_ours = kept(base, ours);
+    let in_theirs = kept(base, theirs);
+    let our_marker = format!("<<<<<<< {}", labels[0]);
+    let their_marker = format!(">>>>>>> {}", labels[1]);
+
+    let mut merged = Vec::new();
+    let mut conflicts = 0;
+    let (mut base_idx, mut our_idx, mut their_idx) = (0, 0, 0);
+    loop {
+        // Both versions kept this base line, so everything
+        // up to it can be merged separately.
+        let stable = (base_idx..base_lines.len())
+            .find_map(|idx| Some((idx, in_ours[idx]?, in_theirs[idx]?)));
+        let (base_end, our_end, their_end) = stable.unwrap_or((
+            base_lines.len(),
+            our_lines.len(),
+            their_lines.len(),
+        ));
+
+        let base_chunk = &base_lines[base_idx..base_end];
+        let our_chunk = &our_lines[our_idx..our_end];
+        let their_chunk = &their_lines[their_idx..their_end];
+        if our_chunk == base_chunk {
+            merged.extend_from_slice(their_chunk);
+        } else if their_chunk == base_chunk || their_chunk == our_chunk {
+            merged.extend_from_slice(our_chunk);
+        } else {
+            conflicts += 1;
+            merged.push(&our_marker);
+            merged.extend_from_slice(our_chunk);
+            merged.push("=======");
+            merged.extend_from_slice(their_chunk);
+            merged.push(&their_marker);
+        }
+
+        let Some((base_end, our_end, their_end)) = stable else {
+            break;
+        };
+        merged.push(base_lines[base_end]);
+        base_idx = base_end + 1;
+        our_idx = our_end + 1;
+        their_idx = their_end + 1;
+    }
+
+    Merge {
+        text: merged.join("\n"),
+        conflicts,
+    }
+}
+
+/// Render the difference between two texts as a unified
+/// diff. Changes are shown with `context` unchanged lines
+/// around them, and changes that are close are shown
+/// together.
+pub fn unified(old: &str, new: &str, context: usize) -> String {
+    let diff = lines(old, new);
+    let changes = diff
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, Line::Same(_)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<usize>>();
+
+    // Group changes whose context overlaps into hunks.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for idx in changes {
+        let start = idx.saturating_sub(context);
+        let end = (idx + context + 1).min(diff.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    for (start, end) in hunks {
+        // Line numbers in the old and new texts start at 1.
+        let old_start = 1 + diff[..start]
+            .iter()
+            .filter(|line| !matches!(line, Line::Added(_)))
+            .count();
+        let new_start = 1 + diff[..start]
+            .iter()
+            .filter(|line| !matches!(line, Line::Removed(_)))
+            .count();
+        let hunk = &diff[start..end];
+        let (added, removed) = stats(hunk);
+        let same = hunk.len() - added - removed;
+
+        let _ = writeln!(
+            out,
+            "@@ -{} +{} @@",
+            range(old_start, same + removed),
+            range(new_start, same + added)
+        );
+        for line in hunk {
+            let _ = match line {
+                Line::Same(line) => writeln!(out, " {line}"),
+                Line::Removed(line) => writeln!(out, "-{line}"),
+                Line::Added(line) => writeln!(out, "+{line}"),
+            };
+        }
+    }
+    out
+}
+
+/// Format a range of lines like `diff -u` does. Empty ranges
+/// start at the line before them.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start - 1),
+        1 => start.to_string(),
+        _ => format!("{start},{len}"),
+    }
+}
+
+/// Cells in the table of common subsequences at most, which
+/// takes about 8 MB.
+const MAX_TABLE: usize = 1 << 20;
+
+use std::fmt::Write;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_work() {
+        let diff = lines("a\nb\nc", "a\nc\nd");
+        assert_eq!(
+            diff,
+            [
+                Line::Same("a"),
+                Line::Removed("b"),
+                Line::Same("c"),
+                Line::Added("d"),
+            ]
+        );
+        assert_eq!(stats(&diff), (1, 1));
+
+        // Too many changed lines are all removed and added.
+        let old = (0..2000).map(|n| format!("{n}\n")).collect::<String>();
+        let new = old.replace('\n', "!\n");
+        let (old, new) = (format!("a\n{old}b"), format!("a\n{new}c"));
+        let diff = lines(&old, &new);
+        assert_eq!(diff.first(), Some(&Line::Same("a")));
+        assert_eq!(stats(&diff[1..]), (2001, 2001));
+    }
+
+    #[test]
+    fn carry_works() {
+        let carried = carry("a\nb\nc", "a\nx\nc\nd", &[1, 2, 3], 4);
+        assert_eq!(carried, [1, 4, 3, 4]);
+    }
+
+    #[test]
+    fn merge_works() {
+        let base = "(define x 1)\n(+ x 1)\n(print x)";
+        let ours = "(define x 2)\n(+ x 1)\n(print x)";
+        let theirs = "(define x 1)\n(+ x 1)\n(print x)\n(* x 2)";
+        let merged = merge(base, ours, theirs, ["ours", "theirs"]);
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(merged.text, "(define x 2)\n(+ x 1)\n(print x)\n(* x 2)");
+
+        let theirs = "(define x 3)\n(+ x 1)\n(print x)";
+        let merged = merge(base, ours, theirs, ["ours", "theirs"]);
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.text,
+            "<<<<<<< ours\n(define x 2)\n=======\n(define x 3)\n>>>>>>> \
+             theirs\n(+ x 1)\n(print x)"
+        );
+
+        // Without a common base, everything that's different
+        // is a conflict.
+        let merged = merge("", "a\nb", "a\nc", ["ours", "theirs"]);
+        assert_eq!(merged.conflicts, 1);
+        assert!(merged.text.starts_with("<<<<<<< ours\na\nb\n=======\na\nc"));
+    }
+
+    #[test]
+    fn unified_works() {
+        let old = (1..=10).map(|n| n.to_string()).collect::<Vec<String>>();
+        let mut new = old.clone();
+        new[1] = "two".to_owned();
+        new.remove(8);
+        let diff = unified(&old.join("\n"), &new.join("\n"), 1);
+        assert_eq!(
+            diff,
+            "@@ -1,3 +1,3 @@\n 1\n-2\n+two\n 3\n@@ -8,3 +8,2 @@\n 8\n-9\n 10\n"
+        );
+
+        assert_eq!(unified("", "x", 3), "@@ -0,0 +1 @@\n+x\n");
+        assert_eq!(unified("x", "x", 3), "");
+    }
+}
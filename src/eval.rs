@@ -14,7 +14,7 @@ impl UserCode {
         S: DiscordCode,
     {
         let indents = match self.balance() {
-            Balanced::NoMissing(n) => n as usize,
+            Balanced::NoMissing(n, _) => n,
             _ => 0,
         };
 
@@ -66,50 +66,263 @@ impl UserCode {
                     deleted = Some(line.trim().to_owned());
                     None
                 } else {
-                    Some(line.to_string())
+                    Some(line)
                 }
             })
-            .collect::<String>();
+            .collect::<Vec<&str>>()
+            .join("\n");
         deleted
     }
 
+    /// Insert code so that it starts at `line`, counting from
+    /// 1. It's indented like the line it's put in front of.
+    pub fn insert<S>(&mut self, line: usize, source: S) -> Result<(), LineError>
+    where
+        S: DiscordCode,
+    {
+        let mut lines = self.0.lines().collect::<Vec<&str>>();
+        if line == 0 || line > lines.len() + 1 {
+            return Err(LineError::NoSuchLine {
+                line,
+                count: lines.len(),
+            });
+        }
+
+        let indent = lines
+            .get(line - 1)
+            .or_else(|| lines.last())
+            .map_or("", |next| indentation(next));
+        let inserted = indented(source.strip_discord_code(), indent);
+        lines.splice(line - 1..line - 1, inserted.iter().map(String::as_str));
+        self.0 = lines.join("\n");
+        Ok(())
+    }
+
+    /// Replace `line`, counting from 1, with some code that
+    /// keeps its indentation. Return the replaced line.
+    pub fn replace<S>(
+        &mut self,
+        line: usize,
+        source: S,
+    ) -> Result<String, LineError>
+    where
+        S: DiscordCode,
+    {
+        let mut lines = self.0.lines().collect::<Vec<&str>>();
+        if line == 0 || line > lines.len() {
+            return Err(LineError::NoSuchLine {
+                line,
+                count: lines.len(),
+            });
+        }
+
+        let replaced = lines[line - 1];
+        let inserted =
+            indented(source.strip_discord_code(), indentation(replaced));
+        let replaced = replaced.trim().to_owned();
+        lines.splice(line - 1..line, inserted.iter().map(String::as_str));
+        self.0 = lines.join("\n");
+        Ok(replaced)
+    }
+
+    /// Get the lines `from` to `to`, counting from 1.
+    pub fn lines(&self, from: usize, to: usize) -> Result<String, LineError> {
+        let lines = self.0.lines().collect::<Vec<&str>>();
+        check_range(from, to, lines.len())?;
+        Ok(lines[from - 1..to].join("\n"))
+    }
+
+    /// Replace the lines `from` to `to`, counting from 1, with
+    /// some code exactly as it is.
+    pub fn replace_lines(
+        &mut self,
+        from: usize,
+        to: usize,
+        source: &str,
+    ) -> Result<(), LineError> {
+        let mut lines = self.0.lines().collect::<Vec<&str>>();
+        check_range(from, to, lines.len())?;
+        lines.splice(from - 1..to, source.lines());
+        self.0 = lines.join("\n");
+        Ok(())
+    }
+
+    /// Replace the lines that are marked in `chunk` with some
+    /// code. It goes where the first marked line was and is
+    /// indented like appended code. Return `false` and leave
+    /// the code as it is if no lines are marked.
+    pub fn replace_chunk<S>(&mut self, chunk: &[bool], source: S) -> bool
+    where
+        S: DiscordCode,
+    {
+        let lines = self.0.lines().collect::<Vec<&str>>();
+        let Some(start) = chunk.iter().position(|marked| *marked) else {
+            return false;
+        };
+
+        let mut replaced = Self::new(lines[..start].join("\n"));
+        replaced.append(source);
+        let rest = lines[start..]
+            .iter()
+            .zip(chunk[start..].iter().chain(std::iter::repeat(&false)))
+            .filter(|(_, marked)| !**marked)
+            .map(|(line, _)| *line)
+            .collect::<Vec<&str>>();
+        if !rest.is_empty() {
+            if !replaced.0.is_empty() {
+                replaced.0.push('\n');
+            }
+            replaced.0.push_str(&rest.join("\n"));
+        }
+        *self = replaced;
+        true
+    }
+
+    /// Show the code with line numbers in front of each line.
+    pub fn numbered(&self) -> String {
+        let width = self.0.lines().count().to_string().len();
+        self.0
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| format!("{:>width$} | {line}", idx + 1))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
     /// Are the parentheses in the source code balanced?
+    /// Parentheses in strings and comments don't count.
     fn balance(&self) -> Balanced {
-        let mut n_opened: i32 = 0;
-        for c in self.0.chars() {
-            match c {
-                '(' => n_opened += 1,
-                ')' => n_opened -= 1,
+        let mut opened = Vec::new();
+        let mut n_trailing = 0;
+        let mut first_trailing = None;
+        for token in Lexer::new(&self.0) {
+            match token.kind {
+                TokenKind::Open => opened.push(token.start),
+                // Match closing parentheses with the last opened one.
+                TokenKind::Close if opened.pop().is_none() => {
+                    n_trailing += 1;
+                    first_trailing.get_or_insert(token.start);
+                },
                 _ => {},
             }
         }
-        match n_opened {
-            0 => Balanced::Yes,
-            i32::MIN..=-1 => Balanced::NoTrailing(n_opened.unsigned_abs()),
-            #[allow(clippy::cast_sign_loss)]
-            1..=i32::MAX => Balanced::NoMissing(n_opened as u32),
+
+        match (first_trailing, opened.first()) {
+            (Some(first), _) => Balanced::NoTrailing(n_trailing, first),
+            (None, Some(first)) => Balanced::NoMissing(opened.len(), *first),
+            (None, None) => Balanced::Yes,
+        }
+    }
+
+    /// Evaluate the code once it's valid.
+    pub fn eval(&self, budget: EvalBudget) -> Option<Evaluation> {
+        if !matches!(self.balance(), Balanced::Yes) {
+            return None;
+        }
+
+        let mut env = LizEnv::new(budget);
+        for form in lexer::forms(&self.0) {
+            env.eval(&form);
         }
+        Some(env.finish())
     }
 
-    fn eval(&self) -> String {
-        let mut env = LizEnv::new();
-        for sexpr in parse(&self.0).flatten() {
-            env.eval(sexpr);
+    // Return a response including both the current
+    // code and the result of evaluating it.
+    pub fn respond(
+        &self,
+        evaluation: Option<&Evaluation>,
+        truncation: Truncation,
+    ) -> Response {
+        Response {
+            code: self.0.clone(),
+            results: evaluation.map(|evaluation| evaluation.render(truncation)),
+            // Only code that isn't evaluated needs explaining.
+            note: if evaluation.is_none() {
+                self.diagnose()
+            } else {
+                None
+            },
         }
-        env.to_string()
     }
 
-    // Return a response message including both the
-    // current code and the result of evaluating it.
-    pub fn respond(&self) -> String {
-        let mut response = self.as_discord_code();
+    /// Explain why code that isn't balanced wasn't evaluated
+    /// by pointing at the first unmatched parenthesis.
+    fn diagnose(&self) -> Option<String> {
+        let (position, note) = match self.balance() {
+            Balanced::Yes => return None,
+            Balanced::NoMissing(n, position) => (
+                position,
+                format!(
+                    "This parenthesis is never closed. {} missing.",
+                    count_parens(n)
+                ),
+            ),
+            Balanced::NoTrailing(n, position) => (
+                position,
+                format!(
+                    "This parenthesis was never opened. {} too many.",
+                    count_parens(n)
+                ),
+            ),
+        };
+
+        let line = self.0.lines().nth(position.line - 1).unwrap_or_default();
+        let gutter = position.line.to_string();
+        // Keep tabs so that the caret lines up with the code.
+        let indent = line
+            .chars()
+            .take(position.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
 
-        // Evaluate once the code is valid.
-        if matches!(self.balance(), Balanced::Yes) {
-            response.push_str(&self.eval().as_discord_code());
+        Some(format!(
+            "```\n{gutter} | {line}\n{} | {indent}^\n```\n{note}",
+            " ".repeat(gutter.len())
+        ))
+    }
+}
+
+/// A response to some code. The code, the results of
+/// evaluating it and a note explaining what went wrong
+/// can be shown together or as separate messages.
+#[derive(Debug)]
+pub struct Response {
+    pub code:    String,
+    pub results: Option<String>,
+    pub note:    Option<String>,
+}
+
+impl std::fmt::Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.code.as_discord_code())?;
+        if let Some(results) = &self.results {
+            write!(f, "{}", results.as_discord_code())?;
+        }
+        if let Some(note) = &self.note {
+            write!(f, "\n{note}")?;
         }
+        Ok(())
+    }
+}
+
+/// The whitespace at the start of a line.
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
 
-        response
+/// Put `indent` in front of each line of `code`.
+fn indented(code: &str, indent: &str) -> Vec<String> {
+    code.lines().map(|line| format!("{indent}{line}")).collect()
+}
+
+/// Say how many closing parentheses there are, e.g.
+/// "There are 2 closing parentheses".
+fn count_parens(n: usize) -> String {
+    if n == 1 {
+        "There is 1 closing parenthesis".to_owned()
+    } else {
+        format!("There are {n} closing parentheses")
     }
 }
 
@@ -119,11 +332,15 @@ impl AsRef<str> for UserCode {
     }
 }
 
-#[derive(Debug)]
+/// Whether all parentheses are matched. If not, the
+/// position is where the first unmatched one is.
+#[derive(Debug, PartialEq, Eq)]
 pub enum Balanced {
     Yes,
-    NoMissing(u32),
-    NoTrailing(u32),
+    /// Some opening parentheses are never closed.
+    NoMissing(usize, Position),
+    /// Some closing parentheses were never opened.
+    NoTrailing(usize, Position),
 }
 
 pub trait DiscordCode: AsRef<str> {
@@ -154,16 +371,314 @@ pub trait DiscordCode: AsRef<str> {
 
 impl<T> DiscordCode for T where T: AsRef<str> {}
 
+#[derive(Debug, thiserror::Error)]
+pub enum LineError {
+    #[error("There is no line {line}, the code has {count} lines")]
+    NoSuchLine { line: usize, count: usize },
+    #[error("Line {to} comes before line {from}")]
+    Backwards { from: usize, to: usize },
+}
+
+/// Check that the lines `from` to `to`, counting from 1, are
+/// all in code with `count` lines.
+fn check_range(from: usize, to: usize, count: usize) -> Result<(), LineError> {
+    if from == 0 {
+        Err(LineError::NoSuchLine { line: from, count })
+    } else if to > count {
+        Err(LineError::NoSuchLine { line: to, count })
+    } else if to < from {
+        Err(LineError::Backwards { from, to })
+    } else {
+        Ok(())
+    }
+}
+
+/// Limits on how much work a single evaluation of
+/// some code is allowed to do.
+#[derive(Debug, Clone, Copy)]
+pub struct EvalBudget {
+    /// Wall-clock time that all expressions share.
+    pub time:  Duration,
+    /// Number of function calls that may be made.
+    pub steps: u64,
+    /// Bytes of stack that nested function calls may use.
+    pub stack: usize,
+}
+
+/// Size of the stack of threads that evaluate code. It leaves
+/// plenty of room beyond `EvalBudget::stack` for the frames
+/// that run between two checks of the stack's size.
+pub const EVAL_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+impl Default for EvalBudget {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(2),
+            steps: 100_000,
+            stack: EVAL_STACK_SIZE / 2,
+        }
+    }
+}
+
+/// Keeps track of how much of its budget an
+/// evaluation has used up.
+struct Limiter {
+    budget:     EvalBudget,
+    started:    Instant,
+    steps:      u64,
+    stack_base: usize,
+    exceeded:   Option<EvalError>,
+}
+
+impl Limiter {
+    fn new(budget: EvalBudget) -> Self {
+        Self {
+            budget,
+            started: Instant::now(),
+            steps: 0,
+            stack_base: stack_address(),
+            exceeded: None,
+        }
+    }
+
+    /// Count one step and fail if the budget is used up.
+    fn tick(&mut self) -> Result<(), RuntimeError> {
+        // Running out of stack only fails the current
+        // expression. Unlike the other limits, it frees
+        // up again once the error has unwound the stack.
+        if stack_address().abs_diff(self.stack_base) > self.budget.stack {
+            return Err(RuntimeError {
+                msg: "maximum recursion depth exceeded".to_owned(),
+            });
+        }
+
+        self.spend(1)
+    }
+
+    /// Count `steps` steps and fail if the budget is used up.
+    fn spend(&mut self, steps: u64) -> Result<(), RuntimeError> {
+        if self.exceeded.is_none() {
+            self.steps = self.steps.saturating_add(steps);
+            if self.steps > self.budget.steps {
+                self.exceeded = Some(EvalError::OutOfSteps(self.budget.steps));
+            } else if self.started.elapsed() > self.budget.time {
+                self.exceeded = Some(EvalError::TimedOut(self.budget.time));
+            }
+        }
+
+        match &self.exceeded {
+            None => Ok(()),
+            Some(err) => Err(RuntimeError {
+                msg: err.to_string(),
+            }),
+        }
+    }
+}
+
+/// Get the address of a value on the current thread's stack.
+/// The distance between two of them is how much stack was used
+/// in between.
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::ptr::addr_of!(marker) as usize
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+enum EvalError {
+    #[error("{0}")]
+    Runtime(#[from] RuntimeError),
+    #[error("Evaluation timed out after {} ms", .0.as_millis())]
+    TimedOut(Duration),
+    #[error("Evaluation timed out after {0} steps")]
+    OutOfSteps(u64),
+    #[error("Parse error: {msg} at {position}")]
+    Parse { msg: String, position: Position },
+}
+
+impl EvalError {
+    /// Point a `ParseError` from parsing `form` on its
+    /// own at where it happened in the whole source.
+    fn parse(form: &Form, err: &ParseError) -> Self {
+        // The parser reports byte offsets into `form.text`.
+        let (msg, offset) = err
+            .msg
+            .rsplit_once(" at index ")
+            .and_then(|(msg, idx)| Some((msg, idx.parse::<usize>().ok()?)))
+            .unwrap_or((&err.msg, 0));
+        let position = form
+            .text
+            .get(..offset)
+            .map_or(form.start, |before| form.start.advance(before));
+
+        Self::Parse {
+            msg: msg.to_owned(),
+            position,
+        }
+    }
+}
+
+/// How deeply lists may nest in code that's parsed or evaluated
+/// and in values that are displayed. All recurse once per level.
+const MAX_NESTING: usize = 1000;
+
+fn nested_too_deeply() -> RuntimeError {
+    RuntimeError {
+        msg: format!("Lists are nested more than {MAX_NESTING} levels deep"),
+    }
+}
+
+/// Display a value like `Value`'s `Display` does, but without
+/// the calls to `tick` that `instrument` inserted into the
+/// functions it contains.
+fn display(value: &Value, tick: &Value) -> Result<String, RuntimeError> {
+    let mut out = String::new();
+    write_value(&mut out, value, tick, 0)?;
+    Ok(out)
+}
+
+fn write_value(
+    out: &mut String,
+    value: &Value,
+    tick: &Value,
+    depth: usize,
+) -> Result<(), RuntimeError> {
+    if depth > MAX_NESTING {
+        return Err(nested_too_deeply());
+    }
+
+    match value {
+        Value::List(list) if *list == List::NIL => out.push_str("NIL"),
+        Value::List(list) => {
+            out.push('(');
+            for (idx, value) in list.into_iter().enumerate() {
+                if idx > 0 {
+                    out.push(' ');
+                }
+                write_value(out, &value, tick, depth + 1)?;
+            }
+            out.push(')');
+        },
+        Value::Lambda(lambda) => {
+            out.push_str("<func:");
+            write_lambda(out, lambda, tick, depth)?;
+            out.push('>');
+        },
+        Value::Macro(lambda) => {
+            out.push_str("(macro ");
+            write_lambda(out, lambda, tick, depth)?;
+            out.push(')');
+        },
+        Value::HashMap(map) => {
+            out.push_str("(hash");
+            for (key, value) in map.borrow().iter() {
+                out.push(' ');
+                write_value(out, key, tick, depth + 1)?;
+                out.push(' ');
+                write_value(out, value, tick, depth + 1)?;
+            }
+            out.push(')');
+        },
+        _ => out.push_str(&value.to_string()),
+    }
+    Ok(())
+}
+
+fn write_lambda(
+    out: &mut String,
+    lambda: &Lambda,
+    tick: &Value,
+    depth: usize,
+) -> Result<(), RuntimeError> {
+    let argnames = lambda.argnames.iter().map(|Symbol(name)| name.as_str());
+    let _ = write!(
+        out,
+        "(lambda ({})",
+        argnames.collect::<Vec<&str>>().join(" ")
+    );
+    if let Value::List(body) = lambda.body.as_ref() {
+        let body = body.into_iter().filter(|sexpr| !is_call(sexpr, tick));
+        for sexpr in body {
+            out.push(' ');
+            write_value(out, &sexpr, tick, depth + 1)?;
+        }
+    }
+    out.push(')');
+    Ok(())
+}
+
+/// Whether `sexpr` calls `func` without arguments.
+fn is_call(sexpr: &Value, func: &Value) -> bool {
+    let (Value::List(call), Value::NativeClosure(func)) = (sexpr, func) else {
+        return false;
+    };
+    let calls_func = matches!(
+        call.car(),
+        Ok(Value::NativeClosure(callee)) if Rc::ptr_eq(&callee, func)
+    );
+    calls_func && call.cdr() == List::NIL
+}
+
+/// Insert a call to `tick` at the start of the body of every
+/// function and macro defined in `sexpr`. Each call to a
+/// function defined by the user then counts as one step,
+/// including tail calls that never grow the stack.
+fn instrument(
+    sexpr: &Value,
+    tick: &Value,
+    depth: usize,
+) -> Result<Value, RuntimeError> {
+    if depth > MAX_NESTING {
+        return Err(nested_too_deeply());
+    }
+    let Value::List(list) = sexpr else {
+        return Ok(sexpr.clone());
+    };
+
+    // Number of elements before the body starts.
+    let n_head = match list.car() {
+        Ok(Value::Symbol(Symbol(keyword))) => match keyword.as_str() {
+            // Quoted lists are data, not code.
+            "quote" => return Ok(sexpr.clone()),
+            "lambda" => 2,
+            "defun" | "defmacro" => 3,
+            _ => 0,
+        },
+        _ => 0,
+    };
+
+    let items = list.into_iter().collect::<Vec<Value>>();
+    let (head, body) = items.split_at(n_head.min(items.len()));
+    let mut instrumented = head.to_vec();
+    if n_head > 0 && !body.is_empty() {
+        instrumented.push(Value::List(std::iter::once(tick.clone()).collect()));
+    }
+    for sexpr in body {
+        instrumented.push(instrument(sexpr, tick, depth + 1)?);
+    }
+    Ok(Value::List(instrumented.into_iter().collect()))
+}
+
 struct LizEnv {
     env:         Rc<RefCell<Env>>,
     print_buf:   Rc<RefCell<String>>,
+    limiter:     Rc<RefCell<Limiter>>,
+    tick:        Value,
     expressions: Vec<LizExpression>,
 }
 
 impl LizEnv {
-    fn new() -> Self {
+    fn new(budget: EvalBudget) -> Self {
         let mut env = default_env();
 
+        let limiter = Rc::new(RefCell::new(Limiter::new(budget)));
+        let limiter_ref = limiter.clone();
+        let tick = Value::NativeClosure(Rc::new(RefCell::new(
+            move |_env: Rc<RefCell<Env>>, _args: Vec<Value>| {
+                limiter_ref.borrow_mut().tick()?;
+                Ok(Value::NIL)
+            },
+        )));
+
         // Register a custom print function that writes
         // to a per-env buffer instead of writing to the
         // server's stdout.
@@ -172,104 +687,247 @@ impl LizEnv {
 
         let print_buf = Rc::new(RefCell::new(String::new()));
         let print_buf_ref = print_buf.clone();
+        let tick_ref = tick.clone();
         let print_clo = Rc::new(RefCell::new(
             move |_env: Rc<RefCell<Env>>, args: Vec<Value>| {
                 let expr = require_arg("print", &args, 0)?;
+                let printed = display(expr, &tick_ref)?;
                 let buf = &mut print_buf_ref.borrow_mut();
-                let res = writeln!(buf, "{}", &expr);
-                match res {
-                    Ok(()) => Ok(expr.clone()),
-                    Err(_) => Err(RuntimeError {
-                        msg: "Failed to print output".to_owned(),
-                    }),
-                }
+                buf.push_str(&printed);
+                buf.push('\n');
+                Ok(expr.clone())
             },
         ));
         env.define(print, Value::NativeClosure(print_clo));
 
+        // Lists are dropped recursively, so a long enough list
+        // overflows the stack. `range` would build one for free,
+        // so each element it adds costs a step.
+        let range = Symbol::from("range");
+        env.undefine(&range);
+
+        let limiter_ref = limiter.clone();
+        let range_clo = Rc::new(RefCell::new(
+            move |_env: Rc<RefCell<Env>>, args: Vec<Value>| {
+                let start = require_typed_arg::<IntType>("range", &args, 0)?;
+                let end = require_typed_arg::<IntType>("range", &args, 1)?;
+                let len = u64::try_from(end.saturating_sub(start)).unwrap_or(0);
+                limiter_ref.borrow_mut().spend(len)?;
+                Ok(Value::List((start..end).map(Value::from).collect()))
+            },
+        ));
+        env.define(range, Value::NativeClosure(range_clo));
+
+        // Code passed to `eval` is only known at runtime, so
+        // it must be instrumented right before it's evaluated.
+        let eval = Symbol::from("eval");
+        env.undefine(&eval);
+
+        let tick_ref = tick.clone();
+        let eval_clo = Rc::new(RefCell::new(
+            move |_env: Rc<RefCell<Env>>, args: Vec<Value>| {
+                let expr = require_arg("eval", &args, 0)?;
+                // Evaluating the expression right here would keep
+                // this closure borrowed, so nested calls to `eval`
+                // would panic. A tail call to a plain function is
+                // run after the borrow ends.
+                Ok(Value::TailCall {
+                    func: Rc::new(Value::NativeFunc(|env, args| {
+                        let expr = require_arg("eval", &args, 0)?;
+                        interpreter::eval(env, expr)
+                    })),
+                    args: vec![instrument(expr, &tick_ref, 0)?],
+                })
+            },
+        ));
+        env.define(eval, Value::NativeClosure(eval_clo));
+
         Self {
             env: Rc::new(RefCell::new(env)),
             print_buf,
+            limiter,
+            tick,
             expressions: Vec::new(),
         }
     }
 
-    fn eval(&mut self, sexpr: Value) {
-        let eval_res = interpreter::eval(self.env.clone(), &sexpr);
+    fn eval(&mut self, form: &Form) {
+        // The parser would overflow the stack on deeply nested
+        // code, so it has to be rejected before parsing.
+        if form.depth > MAX_NESTING {
+            let err = EvalError::Parse {
+                msg: nested_too_deeply().msg,
+                position: form.start,
+            };
+            self.push_error(form, &err);
+            return;
+        }
+
+        let mut parsed_any = false;
+        for parsed in parse(form.text) {
+            // Stop evaluating once the budget is used up. The
+            // expression that used it up reports the reason.
+            if self.limiter.borrow().exceeded.is_some() {
+                return;
+            }
+
+            match parsed {
+                Ok(sexpr) => self.eval_sexpr(&sexpr),
+                Err(err) => {
+                    self.push_error(form, &EvalError::parse(form, &err));
+                    // The parser doesn't move on after errors.
+                    return;
+                },
+            }
+            parsed_any = true;
+        }
+
+        if !parsed_any {
+            let err = EvalError::Parse {
+                msg: format!("Unexpected '{}'", form.text),
+                position: form.start,
+            };
+            self.push_error(form, &err);
+        }
+    }
+
+    fn push_error(&mut self, form: &Form, err: &EvalError) {
         self.expressions.push(LizExpression {
-            sexpr,
-            result: eval_res,
+            sexpr: form.text.to_owned(),
+            result: Err(err.to_string()),
+            printed: String::new(),
+        });
+    }
+
+    fn eval_sexpr(&mut self, sexpr: &Value) {
+        let eval_res = instrument(sexpr, &self.tick, 0).and_then(
+            |instrumented| interpreter::eval(self.env.clone(), &instrumented),
+        );
+        let result = match &self.limiter.borrow().exceeded {
+            Some(err) => Err(err.clone()),
+            None => eval_res
+                .and_then(|value| display(&value, &self.tick))
+                .map_err(EvalError::from),
+        };
+        self.expressions.push(LizExpression {
+            sexpr: sexpr.to_string(),
+            result: result.map_err(|err| err.to_string()),
             printed: self.print_buf.borrow().clone(),
         });
         self.print_buf.borrow_mut().clear();
     }
+
+    fn finish(self) -> Evaluation {
+        Evaluation {
+            expressions: self.expressions,
+        }
+    }
 }
 
-impl std::fmt::Display for LizEnv {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+/// The results of evaluating each expression in some code.
+/// Values are already turned into text, so that evaluations
+/// can be sent between threads and processes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Evaluation {
+    expressions: Vec<LizExpression>,
+}
+
+impl Evaluation {
+    /// Turn the results into text, cutting long expressions
+    /// and values short as configured.
+    pub fn render(&self, truncation: Truncation) -> String {
+        let mut out = String::new();
         for LizExpression {
             sexpr,
             result,
             printed,
         } in &self.expressions
         {
-            writeln!(f, ";; {}", sexpr.to_string().truncate_at(16))?;
+            let _ = writeln!(out, ";; {}", sexpr.truncated(truncation.sexpr));
 
             if !printed.is_empty() {
-                writeln!(f, "{printed}")?;
+                let _ = writeln!(out, "{printed}");
             }
 
             match result {
-                Ok(value) => {
-                    let mut value = value.to_string();
-                    write!(f, "{}", value.truncate_at(64))?;
-                },
-                Err(err) => write!(f, "{err}")?,
+                Ok(value) => out.push_str(&value.truncated(truncation.value)),
+                Err(err) => out.push_str(err),
             }
-            writeln!(f)?;
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl std::fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.render(Truncation::default()))
+    }
+}
+
+/// How many characters of each expression and of its value
+/// are shown before the middle is cut out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncation {
+    pub sexpr: usize,
+    pub value: usize,
+}
+
+impl Default for Truncation {
+    fn default() -> Self {
+        Self {
+            sexpr: 16,
+            value: 64,
         }
-        Ok(())
     }
 }
 
 /// Truncate the middle of a string once it
-/// exceeds the given length. Insert dots to
-/// indidcate what's missing.
+/// exceeds the given number of characters. Insert
+/// dots to indicate what's missing.
 trait Truncate {
-    fn truncate_at(&mut self, limit: usize) -> &Self;
-}
-
-impl Truncate for String {
-    fn truncate_at(&mut self, limit: usize) -> &Self {
-        if self.len() > limit {
-            let rem = limit % 2;
-            let n_init = (limit + rem) / 2;
-            // `- 3` accounts for the three dots.
-            let n_end = (limit - rem) / 2 - 3;
-            *self = format!(
-                "{}...{}",
-                &self[..n_init],
-                &self[self.len() - n_end..]
-            );
+    fn truncated(&self, limit: usize) -> String;
+}
+
+impl Truncate for str {
+    fn truncated(&self, limit: usize) -> String {
+        let len = self.chars().count();
+        if len <= limit {
+            return self.to_owned();
         }
-        self
+
+        // The dots count towards the limit, too.
+        let dots = &"..."[..limit.min(3)];
+        let kept = limit - dots.len();
+        let n_init = usize::midpoint(limit, limit % 2).min(kept);
+        let n_end = kept - n_init;
+        let init = self.chars().take(n_init);
+        let end = self.chars().skip(len - n_end);
+        init.chain(dots.chars()).chain(end).collect()
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
 struct LizExpression {
-    sexpr:   Value,
-    result:  Result<Value, RuntimeError>,
+    sexpr:   String,
+    result:  Result<String, String>,
     printed: String,
 }
 
 use std::cell::RefCell;
 use std::fmt::Write;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use rust_lisp::model::{Env, RuntimeError, Symbol, Value};
-use rust_lisp::parser::parse;
-use rust_lisp::utils::require_arg;
+use rust_lisp::model::{
+    Env, IntType, Lambda, List, RuntimeError, Symbol, Value,
+};
+use rust_lisp::parser::{parse, ParseError};
+use rust_lisp::utils::{require_arg, require_typed_arg};
 use rust_lisp::{default_env, interpreter};
+use serde::{Deserialize, Serialize};
+
+use crate::lexer::{self, Form, Lexer, Position, TokenKind};
 
 #[cfg(test)]
 mod tests {
@@ -289,6 +947,23 @@ mod tests {
         assert_eq!("lisp\nblah```".strip_discord_code(), "lisp\nblah");
     }
 
+    #[test]
+    fn replace_chunk_works() {
+        let mut code = UserCode::new("(define x 1)\n(+ x 1)\n(print x)");
+        assert!(code.replace_chunk(&[false, true, false], "(* x 2)"));
+        assert_eq!(code.0, "(define x 1)\n(* x 2)\n(print x)");
+
+        assert!(code.replace_chunk(&[true, false, false], ""));
+        assert_eq!(code.0, "(* x 2)\n(print x)");
+
+        assert!(!code.replace_chunk(&[false, false], "(+ 1 2)"));
+        assert_eq!(code.0, "(* x 2)\n(print x)");
+
+        let mut code = UserCode::new("(define (f x)\n\t(+ x 1))");
+        assert!(code.replace_chunk(&[false, true], "(* x 2))"));
+        assert_eq!(code.0, "(define (f x)\n\t(* x 2))");
+    }
+
     #[test]
     fn append_code_works() {
         let mut code = UserCode::new(
@@ -301,4 +976,234 @@ mod tests {
         code.append(")))");
         assert!(code.0.ends_with("(- n 2))))))"));
     }
+
+    #[test]
+    fn line_editing_works() {
+        let mut code = UserCode::new("(define x 1)\n(+ x\n\t2)\n(print x)");
+        assert_eq!(code.del(1).as_deref(), Some("2)"));
+        assert_eq!(code.as_ref(), "(define x 1)\n(+ x\n(print x)");
+
+        code.insert(3, "3)").unwrap();
+        assert_eq!(code.as_ref(), "(define x 1)\n(+ x\n3)\n(print x)");
+        code.insert(5, "`x`").unwrap();
+        assert!(code.as_ref().ends_with("(print x)\nx"));
+        assert!(code.insert(7, "x").is_err());
+
+        let mut code = UserCode::new("(+ 1\n\t(* 2 3))");
+        assert_eq!(code.replace(2, "(- 2 3))").unwrap(), "(* 2 3))");
+        assert_eq!(code.as_ref(), "(+ 1\n\t(- 2 3))");
+        assert!(code.replace(3, "x").is_err());
+
+        let lines = (1..=10).map(|n| n.to_string()).collect::<Vec<String>>();
+        let code = UserCode::new(lines.join("\n"));
+        assert!(code.numbered().starts_with(" 1 | 1\n 2 | 2"));
+        assert!(code.numbered().ends_with("10 | 10"));
+    }
+
+    #[test]
+    fn line_ranges_work() {
+        let mut code = UserCode::new("(define (f x)\n\t(+ x 1))\n(f 2)");
+        assert_eq!(code.lines(1, 2).unwrap(), "(define (f x)\n\t(+ x 1))");
+        assert!(code.lines(2, 4).is_err());
+        assert!(code.lines(2, 1).is_err());
+
+        code.replace_lines(2, 2, "  (* x\n     2))").unwrap();
+        assert_eq!(code.as_ref(), "(define (f x)\n  (* x\n     2))\n(f 2)");
+        code.replace_lines(1, 4, "(+ 1 2)\n").unwrap();
+        assert_eq!(code.as_ref(), "(+ 1 2)");
+        assert!(code.replace_lines(0, 1, "x").is_err());
+    }
+
+    #[test]
+    fn balance_works() {
+        let code = UserCode::new("(define s \"(:\") ;; (\n(print s)");
+        assert_eq!(code.balance(), Balanced::Yes);
+
+        let code = UserCode::new("(define x ;; )\n\t(+ 1 \")\"");
+        let Balanced::NoMissing(2, first) = code.balance() else {
+            panic!("Expected two missing parentheses");
+        };
+        assert_eq!((first.line, first.column), (1, 1));
+
+        let code = UserCode::new("(+ 1 2))\n)");
+        let Balanced::NoTrailing(2, first) = code.balance() else {
+            panic!("Expected two trailing parentheses");
+        };
+        assert_eq!((first.line, first.column), (1, 8));
+    }
+
+    #[test]
+    fn unbalanced_code_is_diagnosed() {
+        let code = UserCode::new("(define x 1)\n\t(+ x (* 2 3)");
+        let response = code.respond(None, Truncation::default()).to_string();
+        assert!(response.ends_with(
+            "```\n2 | \t(+ x (* 2 3)\n  | \t^\n```\nThis parenthesis is never \
+             closed. There is 1 closing parenthesis missing."
+        ));
+
+        let code = UserCode::new("(+ 1 2)))");
+        let response = code.respond(None, Truncation::default()).to_string();
+        assert!(response.contains("There are 2 closing parentheses"));
+    }
+
+    #[test]
+    fn infinite_loops_run_out_of_budget() {
+        let code = UserCode::new(
+            "(define loop (lambda () (loop)))\n(loop)\n(+ 1 2)",
+        );
+        let out = code.eval(EvalBudget {
+            time: Duration::from_secs(60),
+            steps: 1000,
+            ..Default::default()
+        })
+        .unwrap()
+        .to_string();
+        assert!(out.contains("Evaluation timed out after 1000 steps"));
+        // Instrumenting functions doesn't change how they look.
+        assert!(out.contains("(lambda () (loop))"));
+        // Nothing is evaluated after the budget is used up.
+        assert!(!out.contains("3"));
+
+        let code = UserCode::new("(define loop (lambda () (loop)))\n(loop)");
+        let out = code.eval(EvalBudget {
+            time: Duration::from_millis(50),
+            steps: u64::MAX,
+            ..Default::default()
+        })
+        .unwrap()
+        .to_string();
+        assert!(out.contains("Evaluation timed out after 50 ms"));
+
+        // Code that only exists at runtime is limited, too.
+        let code = UserCode::new(
+            "(eval '(define loop (lambda () (loop))))\n(loop)",
+        );
+        let out = code.eval(EvalBudget {
+            time: Duration::from_secs(60),
+            steps: 1000,
+            ..Default::default()
+        })
+        .unwrap()
+        .to_string();
+        assert!(out.contains("Evaluation timed out after 1000 steps"));
+    }
+
+    #[test]
+    fn truncation_respects_characters() {
+        assert_eq!("(+ 1 2)".truncated(16), "(+ 1 2)");
+        let alphabet = "abcdefghijklmnopqrstuvwxyz";
+        assert_eq!(alphabet.truncated(16), "abcdefgh...vwxyz");
+        assert_eq!("äöüäöüäöüäöüäöüäöü".truncated(10), "äöüäö...öü");
+        assert_eq!("🦀🦀🦀🦀🦀🦀🦀🦀🦀".truncated(8), "🦀🦀🦀🦀...🦀");
+        assert_eq!("abcdef".truncated(2), "..");
+        assert_eq!("abcdef".truncated(4), "a...");
+        for limit in 0..8 {
+            assert_eq!(alphabet.truncated(limit).chars().count(), limit);
+        }
+    }
+
+    #[test]
+    fn parse_errors_are_reported() {
+        let code = UserCode::new("(define x 1)\n(+ x\n\t\"a\" 2.)\n(+ x 1)");
+        let out = code.eval(EvalBudget::default()).unwrap().to_string();
+        assert!(out.contains(
+            "Parse error: Expected decimal value after '.' at line 3, column 7"
+        ));
+        // Code after parse errors is still evaluated.
+        assert!(out.ends_with("2\n"));
+    }
+
+    #[test]
+    fn deep_recursion_is_an_error() {
+        // Evaluate on a thread with a stack as big as the
+        // evaluator pool's threads have.
+        let out = std::thread::Builder::new()
+            .stack_size(EVAL_STACK_SIZE)
+            .spawn(|| {
+                let code = UserCode::new(
+                    "(define count (lambda (n)\n\
+                     \t(if (== n 0) 0 (+ 1 (count (- n 1))))))\n\
+                     (count 10000000)\n\
+                     (count 100)",
+                );
+                code.eval(EvalBudget {
+                    steps: u64::MAX,
+                    time: Duration::from_secs(60),
+                    ..Default::default()
+                })
+                .unwrap()
+                .to_string()
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(out.contains("maximum recursion depth exceeded"));
+        // Evaluation goes on after the stack has unwound.
+        assert!(out.ends_with("100\n"));
+    }
+
+    #[test]
+    fn deeply_nested_values_are_an_error() {
+        let eval = |source: String| {
+            std::thread::Builder::new()
+                .stack_size(EVAL_STACK_SIZE)
+                .spawn(move || {
+                    let code = UserCode::new(source);
+                    code.eval(EvalBudget {
+                        time: Duration::from_secs(60),
+                        ..Default::default()
+                    })
+                    .unwrap()
+                    .to_string()
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        };
+
+        // Tail calls build values that are nested deeper than
+        // the stack would allow to display or to evaluate.
+        let build = "(define build (lambda (n acc)\n\
+                     \t(if (== n 0) acc (build (- n 1) (list acc)))))";
+        let out = eval(format!("{build}\n(define x (build 99000 nil))\n1"));
+        assert!(out.contains("Lists are nested more than 1000 levels deep"));
+        assert!(out.ends_with("1\n"));
+
+        let out = eval(format!("{build}\n(eval (build 2000 '(+ 1 2)))"));
+        assert!(out.contains("Lists are nested more than 1000 levels deep"));
+
+        let out = eval(
+            "(length (range 0 100))\n(length (range 0 10000000))".to_owned(),
+        );
+        assert!(out.contains("100\n"));
+        assert!(out.contains("Evaluation timed out after 100000 steps"));
+
+        // Code that's nested too deeply can't even be parsed.
+        let out = eval(format!(
+            "{}{}\n1",
+            "(".repeat(100_000),
+            ")".repeat(100_000)
+        ));
+        assert!(out.contains(
+            "Parse error: Lists are nested more than 1000 levels deep at \
+             line 1, column 1"
+        ));
+        assert!(out.ends_with("1\n"));
+    }
+
+    #[test]
+    fn values_are_displayed_as_written() {
+        let code = UserCode::new(
+            "(define inc (lambda (x) (+ x 1)))\n\
+             \"(<closure_function>) \"\n\
+             (hash 1 (list 2 \"3\"))",
+        );
+        let out = code.eval(EvalBudget::default()).unwrap().render(Truncation {
+            sexpr: usize::MAX,
+            value: usize::MAX,
+        });
+        assert!(out.contains("<func:(lambda (x) (+ x 1))>"));
+        assert!(out.contains("\"(<closure_function>) \""));
+        assert!(out.contains("(hash 1 (2 \"3\"))"));
+    }
 }
@@ -0,0 +1,228 @@
+/// Where something is in the source code. Lines
+/// and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Byte offset into the source code.
+    pub offset: usize,
+    pub line:   usize,
+    pub column: usize,
+}
+
+impl Position {
+    const START: Self = Self {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+
+    /// Move the position past `text`.
+    pub fn advance(mut self, text: &str) -> Self {
+        for c in text.chars() {
+            self.offset += c.len_utf8();
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Open,
+    Close,
+    /// `'` or `,` in front of an expression.
+    Quote,
+    String,
+    Atom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub kind:  TokenKind,
+    pub start: Position,
+    /// Byte offset right after the token.
+    pub end:   usize,
+}
+
+/// Split source code into tokens the same way `rust_lisp`'s
+/// parser does. Whitespace and comments are skipped.
+pub struct Lexer<'a> {
+    source: &'a str,
+    pos:    Position,
+}
+
+impl<'a> Lexer<'a> {
+    pub const fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            pos: Position::START,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos.offset..]
+    }
+
+    /// Move past the first `len` bytes of the rest of the source.
+    fn skip(&mut self, len: usize) {
+        self.pos = self.pos.advance(&self.rest()[..len]);
+    }
+
+    /// Move past whitespace and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            let rest = self.rest();
+            if rest.starts_with(";;") {
+                // Comments go on until the end of the line.
+                self.skip(rest.find('\n').unwrap_or(rest.len()));
+            } else if let Some(c) = rest
+                .chars()
+                .next()
+                .filter(|c| c.is_whitespace() || *c == ';')
+            {
+                // Lone semicolons are ignored like whitespace.
+                self.skip(c.len_utf8());
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        self.skip_blank();
+
+        let rest = self.rest();
+        let start = self.pos;
+        let (kind, len) = match rest.chars().next()? {
+            '(' => (TokenKind::Open, 1),
+            ')' => (TokenKind::Close, 1),
+            '\'' | ',' => (TokenKind::Quote, 1),
+            // Strings don't have escapes. They go on until the
+            // next double quote, or until the end if there is none.
+            '"' => (
+                TokenKind::String,
+                rest[1..].find('"').map_or(rest.len(), |end| end + 2),
+            ),
+            _ => (
+                TokenKind::Atom,
+                rest.find(|c: char| c.is_whitespace() || SPECIAL.contains(&c))
+                    .unwrap_or(rest.len()),
+            ),
+        };
+        self.skip(len);
+
+        Some(Token {
+            kind,
+            start,
+            end: self.pos.offset,
+        })
+    }
+}
+
+/// Characters that end an atom.
+const SPECIAL: [char; 5] = ['(', ')', '\'', ',', ';'];
+
+/// A top-level expression in some source code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Form<'a> {
+    pub text:  &'a str,
+    pub start: Position,
+    /// How deeply lists and quotes nest in the expression. The
+    /// parser recurses once per level.
+    pub depth: usize,
+}
+
+/// Split source code into its top-level expressions. An
+/// expression that is never closed goes on until the end.
+pub fn forms(source: &str) -> Vec<Form<'_>> {
+    let mut forms = Vec::new();
+    let mut start = None;
+    // The nesting inside each list that's still open.
+    let mut opened: Vec<usize> = Vec::new();
+    // Quotes in front of the next expression. Each one nests it
+    // in a `(quote ...)`.
+    let mut quotes = 0;
+    let mut depth = 0;
+    for token in Lexer::new(source) {
+        let form_start = *start.get_or_insert(token.start);
+        let nesting = opened.last().copied().unwrap_or(0) + quotes;
+        match token.kind {
+            TokenKind::Open => {
+                opened.push(nesting + 1);
+                depth = depth.max(nesting + 1);
+            },
+            TokenKind::Close => {
+                opened.pop();
+            },
+            // The quoted expression belongs to the same form.
+            TokenKind::Quote => {
+                quotes += 1;
+                continue;
+            },
+            TokenKind::String | TokenKind::Atom => {
+                depth = depth.max(nesting);
+            },
+        }
+        quotes = 0;
+
+        if opened.is_empty() {
+            forms.push(Form {
+                text: &source[form_start.offset..token.end],
+                start: form_start,
+                depth,
+            });
+            start = None;
+            depth = 0;
+        }
+    }
+
+    if let Some(start) = start {
+        forms.push(Form {
+            text: &source[start.offset..],
+            start,
+            depth: depth.max(quotes),
+        });
+    }
+
+    forms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forms_work() {
+        let source = ";; Comment (\n(define s \"(:\")\n'(1 2)\tx\n(+ 1";
+        let texts = forms(source)
+            .iter()
+            .map(|form| form.text)
+            .collect::<Vec<&str>>();
+        assert_eq!(texts, ["(define s \"(:\")", "'(1 2)", "x", "(+ 1"]);
+
+        let last = forms(source)[3].start;
+        assert_eq!((last.line, last.column), (4, 1));
+        assert_eq!(forms(source)[2].start.column, 8);
+
+        let depths = forms(source)
+            .iter()
+            .map(|form| form.depth)
+            .collect::<Vec<usize>>();
+        assert_eq!(depths, [1, 2, 0, 1]);
+        assert_eq!(forms("(a '('b))")[0].depth, 4);
+    }
+}
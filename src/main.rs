@@ -18,18 +18,22 @@ async fn serenity(
     #[cfg(not(debug_assertions))]
     let api_token = get_secrets(&secret_store)?;
 
-    let intents =
-        GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::MESSAGE_CONTENT;
 
     sqlx::migrate!("./migrations")
         .run(&pool)
         .await
         .context("Failed to migrate database".to_owned())?;
 
+    let evaluator = get_evaluator(&secret_store)?;
+    let max_idle = get_max_idle(&secret_store)?;
+
     #[cfg(debug_assertions)]
-    let bot = Bot::new(pool, guild_id);
+    let bot = Bot::new(pool, evaluator, max_idle, guild_id);
     #[cfg(not(debug_assertions))]
-    let bot = Bot::new(pool);
+    let bot = Bot::new(pool, evaluator, max_idle);
 
     let client = Client::builder(&api_token, intents)
         .event_handler(bot)
@@ -71,6 +75,69 @@ fn get_secrets(secret_store: &SecretStore) -> anyhow::Result<String> {
     Ok(token)
 }
 
+/// Choose how code is evaluated using `EVALUATOR`. It's
+/// either `threads` (the default) to evaluate in the bot's
+/// own process or `process` to evaluate in worker processes.
+fn get_evaluator(secret_store: &SecretStore) -> anyhow::Result<Evaluator> {
+    match secret_store.get("EVALUATOR").as_deref() {
+        None | Some("threads") => {
+            info!("Evaluating code on threads");
+            let n_threads = get_eval_threads(secret_store)?;
+            Ok(Evaluator::Threads(EvalPool::new(n_threads, EVAL_TIMEOUT)))
+        },
+        Some("process") => {
+            info!("Evaluating code in worker processes");
+            Ok(Evaluator::Process(Sandbox::new(EVAL_TIMEOUT)?))
+        },
+        Some(other) => Err(anyhow!("'EVALUATOR' was not valid: {}", other)),
+    }
+}
+
+/// Get the number of evaluator threads from `EVAL_THREADS`.
+/// There is one thread per CPU if it's not set.
+fn get_eval_threads(
+    secret_store: &SecretStore,
+) -> anyhow::Result<NonZeroUsize> {
+    match secret_store.get("EVAL_THREADS") {
+        Some(n_threads_str) => match n_threads_str.parse::<NonZeroUsize>() {
+            Ok(n_threads) => Ok(n_threads),
+            Err(e) => Err(anyhow!("'EVAL_THREADS' was not valid: {}", e)),
+        },
+        None => thread::available_parallelism()
+            .context("Failed to get the number of CPUs".to_owned()),
+    }
+}
+
+/// Get how long sessions may be idle before they're closed
+/// from `SESSION_MAX_IDLE_DAYS`. It's 30 days if it's not set.
+fn get_max_idle(secret_store: &SecretStore) -> anyhow::Result<Duration> {
+    let days = match secret_store.get("SESSION_MAX_IDLE_DAYS") {
+        Some(days_str) => match days_str.parse::<u64>() {
+            Ok(days) => days,
+            Err(e) => {
+                return Err(anyhow!(
+                    "'SESSION_MAX_IDLE_DAYS' was not valid: {}",
+                    e
+                ));
+            },
+        },
+        None => DEFAULT_MAX_IDLE_DAYS,
+    };
+    days.checked_mul(24 * 60 * 60)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("'SESSION_MAX_IDLE_DAYS' was too large"))
+}
+
+/// How long to wait for evaluation to finish. This is longer than
+/// the time evaluation is allowed to take, so it only applies if
+/// an evaluator thread gets stuck.
+const EVAL_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_IDLE_DAYS: u64 = 30;
+
+use std::num::NonZeroUsize;
+use std::thread;
+use std::time::Duration;
+
 use anyhow::{anyhow, Context};
 use serenity::client::Client;
 use serenity::model::gateway::GatewayIntents;
@@ -81,6 +148,10 @@ use sqlx::PgPool;
 use tracing::info;
 
 mod bot;
-mod eval;
+mod output;
+mod pool;
+mod sandbox;
 
-use crate::bot::Bot;
+use crate::bot::{Bot, Evaluator};
+use crate::pool::EvalPool;
+use crate::sandbox::Sandbox;
@@ -0,0 +1,501 @@
+/// A reply split into messages that fit within Discord's
+/// limit on the length of messages.
+pub struct Reply {
+    parts:     Vec<Part>,
+    /// Buttons under the last message.
+    buttons:   Vec<Button>,
+    /// Only show the reply to whoever caused it. This only
+    /// works when responding to interactions.
+    ephemeral: bool,
+    /// Replace the message whose button was clicked instead
+    /// of sending a new one.
+    replace:   bool,
+}
+
+/// One message of a reply. Text that's too long for a
+/// message is attached as a file instead.
+enum Part {
+    Text(String),
+    File { name: &'static str, content: String },
+}
+
+/// The text, the attachment and the buttons of a message.
+type Outgoing = (String, Option<AttachmentType<'static>>, Vec<Button>);
+
+/// How an interaction was acknowledged before its reply was
+/// ready. Discord gives up on responses after three seconds.
+#[derive(Debug, Clone, Copy)]
+pub enum Deferral {
+    /// Show that the bot is thinking in a new message.
+    Message { ephemeral: bool },
+    /// Keep the message whose component was used as it is.
+    Update,
+}
+
+/// A button that sends an interaction with its ID when
+/// it's clicked.
+pub struct Button {
+    id:    String,
+    label: String,
+    style: ButtonStyle,
+}
+
+impl Deferral {
+    /// The kind of response that acknowledges an interaction
+    /// like this.
+    const fn kind(self) -> InteractionResponseType {
+        match self {
+            Self::Message { .. } => {
+                InteractionResponseType::DeferredChannelMessageWithSource
+            },
+            Self::Update => InteractionResponseType::DeferredUpdateMessage,
+        }
+    }
+}
+
+impl Part {
+    /// Use `text` if it fits into a message and attach
+    /// `content` as a file called `name` otherwise.
+    fn text_or_file(text: String, name: &'static str, content: &str) -> Self {
+        if fits(&text) {
+            Self::Text(text)
+        } else {
+            Self::File {
+                name,
+                content: content.to_owned(),
+            }
+        }
+    }
+
+    /// Get the message's text and its attachment.
+    fn into_message(self) -> (String, Option<AttachmentType<'static>>) {
+        match self {
+            Self::Text(text) => (text, None),
+            Self::File { name, content } => (
+                format!("This is too long for a message, see `{name}`."),
+                Some(AttachmentType::Bytes {
+                    data: Cow::Owned(content.into_bytes()),
+                    filename: name.to_owned(),
+                }),
+            ),
+        }
+    }
+}
+
+impl Reply {
+    fn new(parts: Vec<Part>) -> Self {
+        Self {
+            parts,
+            buttons: Vec::new(),
+            ephemeral: false,
+            replace: false,
+        }
+    }
+
+    /// Add a message with code in a code block. It's attached
+    /// as a file called `name` if it's too long.
+    pub fn with_code(
+        mut self,
+        language: &str,
+        code: &str,
+        name: &'static str,
+    ) -> Self {
+        let text = format!("```{language}\n{code}\n```");
+        self.parts.push(Part::text_or_file(text, name, code));
+        self
+    }
+
+    /// Add a button under the last message.
+    pub fn with_button<S>(
+        mut self,
+        id: S,
+        label: &str,
+        style: ButtonStyle,
+    ) -> Self
+    where
+        S: Into<String>,
+    {
+        self.buttons.push(Button {
+            id: id.into(),
+            label: label.to_owned(),
+            style,
+        });
+        self
+    }
+
+    /// Only show the reply to whoever caused it.
+    pub const fn ephemeral(mut self) -> Self {
+        self.ephemeral = true;
+        self
+    }
+
+    /// Replace the message whose button was clicked. This
+    /// only works when responding to buttons.
+    pub const fn replacing(mut self) -> Self {
+        self.replace = true;
+        self
+    }
+
+    /// Get each message. Only the last one has buttons.
+    fn into_messages(self) -> impl Iterator<Item = Outgoing> {
+        let mut buttons = Some(self.buttons);
+        let last = self.parts.len().saturating_sub(1);
+        self.parts.into_iter().enumerate().map(move |(idx, part)| {
+            let (text, file) = part.into_message();
+            let buttons = if idx == last {
+                buttons.take().unwrap_or_default()
+            } else {
+                Vec::new()
+            };
+            (text, file, buttons)
+        })
+    }
+
+    /// Send the reply as messages in a channel.
+    pub async fn send(self, http: &Http, channel: ChannelId) -> Result<()> {
+        for (text, file, buttons) in self.into_messages() {
+            channel
+                .send_message(http, |message| {
+                    if let Some(file) = file {
+                        message.add_file(file);
+                    }
+                    if !buttons.is_empty() {
+                        message.components(|c| add_buttons(c, &buttons));
+                    }
+                    message.content(text)
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Respond to an interaction. Messages after the first
+    /// one are sent as follow-ups, even when replacing.
+    pub async fn respond_to<I>(
+        self,
+        http: &Http,
+        interaction: &I,
+    ) -> Result<()>
+    where
+        I: Interactive,
+    {
+        let (ephemeral, replace) = (self.ephemeral, self.replace);
+        let mut messages = self.into_messages();
+        let first = messages
+            .next()
+            .unwrap_or_else(|| (String::new(), None, Vec::new()));
+        interaction.respond(http, first, ephemeral, replace).await?;
+
+        for message in messages {
+            interaction.follow_up(http, message, ephemeral).await?;
+        }
+        Ok(())
+    }
+
+    /// Respond to an interaction that was deferred. The first
+    /// message replaces the deferred response if it's visible
+    /// to the same people. Replies to a private deferred
+    /// response stay private.
+    pub async fn respond_deferred<I>(
+        self,
+        http: &Http,
+        interaction: &I,
+        deferral: Deferral,
+    ) -> Result<()>
+    where
+        I: Interactive,
+    {
+        let (ephemeral, replace) = (self.ephemeral, self.replace);
+        let edit_first = match deferral {
+            Deferral::Message { ephemeral: true } => true,
+            Deferral::Message { ephemeral: false } if ephemeral => {
+                // The bot's public thinking message goes away.
+                interaction.delete_response(http).await?;
+                false
+            },
+            Deferral::Message { ephemeral: false } => true,
+            Deferral::Update => replace,
+        };
+
+        let mut messages = self.into_messages();
+        if edit_first {
+            let first = messages
+                .next()
+                .unwrap_or_else(|| (String::new(), None, Vec::new()));
+            interaction.edit_response(http, first, ephemeral).await?;
+        }
+        for message in messages {
+            interaction.follow_up(http, message, ephemeral).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Interactions that can be responded to with messages.
+#[async_trait]
+pub trait Interactive: Sync {
+    /// Send the first message in response, or replace the
+    /// message that the interaction came from.
+    async fn respond(
+        &self,
+        http: &Http,
+        message: Outgoing,
+        ephemeral: bool,
+        replace: bool,
+    ) -> Result<()>;
+
+    /// Send another message after the first one.
+    async fn follow_up(
+        &self,
+        http: &Http,
+        message: Outgoing,
+        ephemeral: bool,
+    ) -> Result<()>;
+
+    /// Acknowledge the interaction before its reply is ready.
+    async fn defer_response(
+        &self,
+        http: &Http,
+        deferral: Deferral,
+    ) -> Result<()>;
+
+    /// Replace the deferred response, or the message whose
+    /// component was used, with a message.
+    async fn edit_response(
+        &self,
+        http: &Http,
+        message: Outgoing,
+        ephemeral: bool,
+    ) -> Result<()>;
+
+    /// Delete the deferred response.
+    async fn delete_response(&self, http: &Http) -> Result<()>;
+}
+
+/// Implement `Interactive` for interactions, which all have
+/// the same methods, but no trait for them.
+macro_rules! impl_interactive {
+    ($($interaction:ty),*) => {$(
+        #[async_trait]
+        impl Interactive for $interaction {
+            async fn respond(
+                &self,
+                http: &Http,
+                (text, file, buttons): Outgoing,
+                ephemeral: bool,
+                replace: bool,
+            ) -> Result<()> {
+                let kind = if replace {
+                    InteractionResponseType::UpdateMessage
+                } else {
+                    InteractionResponseType::ChannelMessageWithSource
+                };
+                self.create_interaction_response(http, |response| {
+                    response.kind(kind).interaction_response_data(|message| {
+                        if let Some(file) = file {
+                            message.add_file(file);
+                        }
+                        // The replaced message's buttons are removed
+                        // unless there are new ones.
+                        if !buttons.is_empty() || replace {
+                            message.components(|c| add_buttons(c, &buttons));
+                        }
+                        message.content(text).ephemeral(ephemeral)
+                    })
+                })
+                .await
+            }
+
+            async fn follow_up(
+                &self,
+                http: &Http,
+                (text, file, buttons): Outgoing,
+                ephemeral: bool,
+            ) -> Result<()> {
+                self.create_followup_message(http, |message| {
+                    if let Some(file) = file {
+                        message.add_file(file);
+                    }
+                    if !buttons.is_empty() {
+                        message.components(|c| add_buttons(c, &buttons));
+                    }
+                    message.content(text).ephemeral(ephemeral)
+                })
+                .await?;
+                Ok(())
+            }
+
+            async fn defer_response(
+                &self,
+                http: &Http,
+                deferral: Deferral,
+            ) -> Result<()> {
+                let ephemeral =
+                    matches!(deferral, Deferral::Message { ephemeral: true });
+                self.create_interaction_response(http, |response| {
+                    response.kind(deferral.kind()).interaction_response_data(
+                        |message| message.ephemeral(ephemeral),
+                    )
+                })
+                .await
+            }
+
+            async fn edit_response(
+                &self,
+                http: &Http,
+                (text, file, buttons): Outgoing,
+                ephemeral: bool,
+            ) -> Result<()> {
+                // Replaced messages lose their buttons unless there
+                // are new ones.
+                self.edit_original_interaction_response(http, |message| {
+                    message
+                        .content(text)
+                        .components(|c| add_buttons(c, &buttons))
+                })
+                .await?;
+
+                // Responses can't be edited to add files.
+                if let Some(file) = file {
+                    self.create_followup_message(http, |message| {
+                        message.add_file(file).ephemeral(ephemeral)
+                    })
+                    .await?;
+                }
+                Ok(())
+            }
+
+            async fn delete_response(&self, http: &Http) -> Result<()> {
+                self.delete_original_interaction_response(http).await
+            }
+        }
+    )*};
+}
+
+impl_interactive!(
+    ApplicationCommandInteraction,
+    MessageComponentInteraction,
+    ModalSubmitInteraction
+);
+
+/// Put buttons into a row of components. There's no row if
+/// there are no buttons.
+fn add_buttons<'a>(
+    components: &'a mut CreateComponents,
+    buttons: &[Button],
+) -> &'a mut CreateComponents {
+    if buttons.is_empty() {
+        return components;
+    }
+    components.create_action_row(|row| {
+        for button in buttons {
+            row.create_button(|create| {
+                create
+                    .custom_id(&button.id)
+                    .label(&button.label)
+                    .style(button.style)
+            });
+        }
+        row
+    })
+}
+
+impl From<String> for Reply {
+    fn from(text: String) -> Self {
+        let part = Part::text_or_file(text.clone(), "message.txt", &text);
+        Self::new(vec![part])
+    }
+}
+
+impl From<Response> for Reply {
+    /// Keep the response in one message if it fits. Otherwise
+    /// send the code, the results and the note separately.
+    fn from(response: Response) -> Self {
+        let whole = response.to_string();
+        if fits(&whole) {
+            return Self::new(vec![Part::Text(whole)]);
+        }
+
+        let mut parts = vec![Part::text_or_file(
+            response.code.as_discord_code(),
+            "code.lisp",
+            &response.code,
+        )];
+        if let Some(results) = &response.results {
+            parts.push(Part::text_or_file(
+                results.as_discord_code(),
+                "results.txt",
+                results,
+            ));
+        }
+        if let Some(note) = response.note {
+            parts.push(Part::text_or_file(note.clone(), "note.txt", &note));
+        }
+        Self::new(parts)
+    }
+}
+
+/// Does the text fit into a single message?
+fn fits(text: &str) -> bool {
+    text.chars().count() <= MESSAGE_LIMIT
+}
+
+/// Discord doesn't allow messages with more characters.
+const MESSAGE_LIMIT: usize = 2000;
+
+use std::borrow::Cow;
+
+use liz::eval::{DiscordCode, Response};
+use serenity::async_trait;
+use serenity::builder::CreateComponents;
+use serenity::http::Http;
+use serenity::model::application::component::ButtonStyle;
+#[rustfmt::skip]
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+#[rustfmt::skip]
+use serenity::model::application::interaction::message_component::MessageComponentInteraction;
+#[rustfmt::skip]
+use serenity::model::application::interaction::modal::ModalSubmitInteraction;
+use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::channel::AttachmentType;
+use serenity::model::id::ChannelId;
+use serenity::Result;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_responses_are_split() {
+        let response = Response {
+            code: "(range 0 1000)".to_owned(),
+            results: Some("0 ".repeat(1000)),
+            note: None,
+        };
+        let reply = Reply::from(response);
+        assert!(matches!(
+            reply.parts.as_slice(),
+            [Part::Text(_), Part::File { name: "results.txt", .. }]
+        ));
+
+        let response = Response {
+            code: "(+ 1 2)".to_owned(),
+            results: Some("3".to_owned()),
+            note: None,
+        };
+        let reply = Reply::from(response);
+        assert!(matches!(reply.parts.as_slice(), [Part::Text(_)]));
+    }
+
+    #[test]
+    fn buttons_go_under_the_last_message() {
+        let reply = Reply::from("a".to_owned())
+            .with_code("diff", "+b", "b.diff")
+            .with_button("id", "Click", ButtonStyle::Primary);
+        let buttons = reply
+            .into_messages()
+            .map(|(_, _, buttons)| buttons.len())
+            .collect::<Vec<usize>>();
+        assert_eq!(buttons, [0, 1]);
+    }
+}
@@ -0,0 +1,96 @@
+/// A fixed set of OS threads that evaluate code. The
+/// interpreter isn't `Send`, so it can't run on the async
+/// runtime without blocking everything else that runs there.
+pub struct EvalPool {
+    jobs:    mpsc::Sender<Job>,
+    timeout: Duration,
+}
+
+struct Job {
+    code:  UserCode,
+    reply: oneshot::Sender<Option<Evaluation>>,
+}
+
+impl EvalPool {
+    /// Start `size` evaluator threads. Waiting for a result
+    /// is given up after `timeout`.
+    pub fn new(size: NonZeroUsize, timeout: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for idx in 0..size.get() {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("liz-eval-{idx}"))
+                .stack_size(EVAL_STACK_SIZE)
+                .spawn(move || work(&receiver))
+                .expect("Failed to spawn evaluator thread");
+        }
+
+        Self {
+            jobs: sender,
+            timeout,
+        }
+    }
+
+    /// Evaluate the code on one of the threads in the pool.
+    pub async fn eval(
+        &self,
+        code: &UserCode,
+    ) -> Result<Option<Evaluation>, PoolError> {
+        let (reply, evaluation) = oneshot::channel();
+        let code = UserCode::new(code);
+        self.jobs
+            .send(Job { code, reply })
+            .map_err(|_| PoolError::Closed)?;
+
+        match timeout(self.timeout, evaluation).await {
+            Err(_) => Err(PoolError::TimedOut(self.timeout)),
+            Ok(Err(_)) => Err(PoolError::Failed),
+            Ok(Ok(evaluation)) => Ok(evaluation),
+        }
+    }
+}
+
+fn work(jobs: &Mutex<mpsc::Receiver<Job>>) {
+    loop {
+        // Only hold the lock while waiting for a job.
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        let Ok(Job { code, reply }) = job else {
+            return;
+        };
+
+        // Keep the thread alive if evaluation panics. Dropping
+        // `reply` tells the waiting handler that it failed.
+        let eval = || code.eval(EvalBudget::default());
+        if let Ok(evaluation) = panic::catch_unwind(eval) {
+            // The handler might have stopped waiting already.
+            let _ = reply.send(evaluation);
+        } else {
+            error!("Evaluator thread panicked");
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PoolError {
+    #[error("Evaluation didn't finish within {} ms", .0.as_millis())]
+    TimedOut(Duration),
+    #[error("Evaluation failed unexpectedly")]
+    Failed,
+    #[error("No evaluator threads are running")]
+    Closed,
+}
+
+use std::num::NonZeroUsize;
+use std::panic;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use liz::eval::{EvalBudget, Evaluation, UserCode, EVAL_STACK_SIZE};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use tracing::error;
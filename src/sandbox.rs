@@ -0,0 +1,93 @@
+/// Evaluates code in a separate `liz-worker` process. The
+/// worker runs with limited resources, and if it crashes, only
+/// the evaluation fails and the bot keeps running.
+pub struct Sandbox {
+    worker:  PathBuf,
+    timeout: Duration,
+}
+
+impl Sandbox {
+    /// Use the `liz-worker` binary next to the bot's own binary.
+    /// Waiting for a worker is given up after `timeout`.
+    pub fn new(timeout: Duration) -> anyhow::Result<Self> {
+        let worker = env::current_exe()
+            .context("Failed to locate the bot's binary")?
+            .with_file_name(WORKER_NAME);
+        if !worker.is_file() {
+            return Err(anyhow!(
+                "Worker binary '{}' doesn't exist",
+                worker.display()
+            ));
+        }
+
+        Ok(Self { worker, timeout })
+    }
+
+    /// Evaluate the code in a new worker process.
+    pub async fn eval(
+        &self,
+        code: &UserCode,
+    ) -> Result<Option<Evaluation>, SandboxError> {
+        let mut child = Command::new(&self.worker)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(SandboxError::Spawn)?;
+
+        let run = async move {
+            if let Some(mut stdin) = child.stdin.take() {
+                // Dropping `stdin` afterwards closes it.
+                stdin.write_all(code.as_ref().as_bytes()).await?;
+            }
+            child.wait_with_output().await
+        };
+
+        // The worker is killed if the timeout drops it.
+        let output = match timeout(self.timeout, run).await {
+            Err(_) => return Err(SandboxError::TimedOut(self.timeout)),
+            Ok(output) => output.map_err(SandboxError::Io)?,
+        };
+
+        if !output.status.success() {
+            error!(
+                "Worker failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(SandboxError::Crashed(output.status));
+        }
+
+        serde_json::from_slice(&output.stdout).map_err(SandboxError::Output)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("Failed to start the evaluator")]
+    Spawn(#[source] io::Error),
+    #[error("Failed to communicate with the evaluator")]
+    Io(#[source] io::Error),
+    #[error("Evaluation didn't finish within {} ms", .0.as_millis())]
+    TimedOut(Duration),
+    #[error("The evaluator crashed ({0})")]
+    Crashed(ExitStatus),
+    #[error("The evaluator returned invalid results")]
+    Output(#[source] serde_json::Error),
+}
+
+const WORKER_NAME: &str = "liz-worker";
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use liz::eval::{Evaluation, UserCode};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time::timeout;
+use tracing::error;